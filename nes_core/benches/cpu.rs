@@ -0,0 +1,97 @@
+//! Instructions per second of `CPU::step` on a few representative programs, and frames
+//! per second of `Nes::run_frame`.
+//!
+//! `cargo bench -p nes_core --bench cpu`; criterion reports the throughput in instructions
+//! or frames (elements) per second.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use nes_core::cpu::{Mem, CPU};
+use nes_core::hooks::MemoryAccess;
+use nes_core::nes::Nes;
+use nes_core::snake;
+
+/// Instructions run per measured iteration.
+const STEPS: u64 = 100_000;
+
+/// LDX #0 / loop: TXA / CLC / ADC #$37 / EOR #$A5 / ASL A / AND #$F0 / ORA #$0F / SEC /
+/// SBC #$11 / ROR A / CMP #$80 / DEX / BNE loop / JMP $0600
+const ALU_LOOP: [u8; 25] = [
+    0xa2, 0x00, 0x8a, 0x18, 0x69, 0x37, 0x49, 0xa5, 0x0a, 0x29, 0xf0, 0x09, 0x0f, 0x38, 0xe9, 0x11, 0x6a,
+    0xc9, 0x80, 0xca, 0xd0, 0xec, 0x4c, 0x00, 0x06,
+];
+
+/// Copies the page at $0200 to $0300 through a zero page pointer, counting bytes in $10:
+/// LDA #$00 / STA $00 / LDA #$02 / STA $01 / start: LDY #0 /
+/// loop: LDA ($00),Y / STA $0300,Y / INC $10 / INY / BNE loop / JMP start
+const MEMORY_LOOP: [u8; 23] = [
+    0xa9, 0x00, 0x85, 0x00, 0xa9, 0x02, 0x85, 0x01, 0xa0, 0x00, 0xb1, 0x00, 0x99, 0x00, 0x03, 0xe6, 0x10,
+    0xc8, 0xd0, 0xf6, 0x4c, 0x08, 0x06,
+];
+
+fn cpu_with(program: &[u8]) -> CPU {
+    let mut cpu = CPU::new();
+    cpu.load(program.to_vec()).unwrap();
+    cpu.reset();
+    cpu
+}
+
+fn run(cpu: &mut CPU) {
+    for _ in 0..STEPS {
+        assert!(cpu.step().unwrap(), "the program halted");
+    }
+}
+
+/// Plays snake with a fixed sequence of keys and random numbers, starting over when the
+/// snake dies.
+fn run_snake(cpu: &mut CPU, seed: &mut u32) {
+    const KEYS: [u8; 4] = [b'w', b'd', b's', b'a'];
+    for step in 0..STEPS {
+        *seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        cpu.mem_write(0xfe, (*seed >> 16) as u8 % 16 + 1);
+        cpu.mem_write(0xff, KEYS[(step / 2_000 % 4) as usize]);
+        if !cpu.step().unwrap() {
+            cpu.reset();
+        }
+    }
+}
+
+fn bench_cpu(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cpu");
+    group.throughput(Throughput::Elements(STEPS));
+
+    let mut cpu = cpu_with(&snake::GAME_CODE);
+    let mut seed = 1;
+    group.bench_function("snake", |b| b.iter(|| run_snake(&mut cpu, &mut seed)));
+
+    let mut cpu = cpu_with(&ALU_LOOP);
+    group.bench_function("alu_loop", |b| b.iter(|| run(&mut cpu)));
+
+    let mut cpu = cpu_with(&MEMORY_LOOP);
+    group.bench_function("memory_loop", |b| b.iter(|| run(&mut cpu)));
+
+    let mut cpu = cpu_with(&MEMORY_LOOP);
+    cpu.cycle_accurate_bus = true;
+    cpu.add_memory_hook(MemoryAccess::all(), 0x0000..=0xffff, |_, _, _| {});
+    group.bench_function("memory_loop_hooked", |b| b.iter(|| run(&mut cpu)));
+
+    group.finish();
+}
+
+fn bench_frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nes");
+    group.throughput(Throughput::Elements(1));
+
+    let mut nes = Nes::snake(1);
+    group.bench_function("snake_frame", |b| {
+        b.iter(|| {
+            if nes.run_frame().is_err() || nes.cpu().halt_reason().is_some() {
+                nes = Nes::snake(1);
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_cpu, bench_frames);
+criterion_main!(benches);
@@ -0,0 +1,707 @@
+use crate::cpu::AddressingMode;
+use crate::cpu::CpuVariant;
+use crate::opcode;
+use crate::opcode::OpCode;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// Where `CPU::load` puts programs.
+pub const DEFAULT_ORIGIN: u16 = 0x0600;
+
+/// Assembles source for `variant`'s instruction set into the bytes `CPU::load` expects.
+///
+/// ```text
+///         .org $0600          ; optional, $0600 is the default
+/// count = 8                   ; constants
+/// start:  LDX #count
+/// loop:   DEX
+///         STA table,X
+///         BNE loop
+///         JMP (vector)
+/// table:  .byte 1, 2, $03, "ab"
+/// vector: .word start, * + 2
+/// ```
+///
+/// Numbers are decimal, `$hex`, `%binary` or `'c'`; expressions support `+ - * / & | ^ << >>`,
+/// parentheses, `<` / `>` for the low / high byte and `*` for the current address.
+/// Operands that fit in a byte use zero page addressing when the instruction has it,
+/// unless they refer to a label defined further down or a constant computed from one.
+///
+/// The output starts at the first `.org` (or $0600); a later `.org` may only move forward
+/// and the gap is filled with zeros.
+pub fn assemble(source: &str, variant: CpuVariant) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler { variant, ..Assembler::default() };
+    for pass in [Pass::Layout, Pass::Emit] {
+        assembler.start(pass);
+        for (line_no, line) in source.lines().enumerate() {
+            assembler
+                .line(line_no, line)
+                .map_err(|e| format!("line {}: {}", line_no + 1, e))?;
+        }
+    }
+    Ok(assembler.out)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Pass {
+    /// assigns addresses to labels
+    Layout,
+    /// produces the bytes once every label is known
+    Emit,
+}
+
+#[derive(Default)]
+struct Assembler {
+    variant: CpuVariant,
+    pass: Option<Pass>,
+    symbols: HashMap<String, i64>,
+    /// lines whose operand used a label defined further down; they are assembled
+    /// with 16-bit operands in both passes so that addresses do not move
+    forward_refs: HashSet<usize>,
+    /// constants whose value used a label defined further down; they read as 0 in the
+    /// layout pass, so operands using them must not be sized from that value
+    forward_constants: HashSet<String>,
+    origin: Option<u16>,
+    pc: u32,
+    /// address of the current line, the value of `*`
+    line_pc: u32,
+    out: Vec<u8>,
+}
+
+enum Operand {
+    Implied,
+    Accumulator,
+    Immediate(Expr),
+    Direct(Expr),
+    DirectX(Expr),
+    DirectY(Expr),
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+type Expr = String;
+
+impl Assembler {
+    fn start(&mut self, pass: Pass) {
+        self.pass = Some(pass);
+        self.origin = None;
+        self.pc = DEFAULT_ORIGIN as u32;
+        self.out.clear();
+    }
+
+    fn line(&mut self, line_no: usize, line: &str) -> Result<(), String> {
+        let mut rest = strip_comment(line).trim();
+        self.line_pc = self.pc;
+
+        if let Some((name, expr)) = rest.split_once('=') {
+            let name = name.trim();
+            if is_identifier(name) {
+                let value = self.eval(line_no, expr)?;
+                if value.is_none() {
+                    self.forward_constants.insert(name.to_string());
+                }
+                return self.define(name, value.unwrap_or(0));
+            }
+        }
+
+        if let Some((label, after)) = rest.split_once(':') {
+            if is_identifier(label.trim()) {
+                self.define(label.trim(), self.pc as i64)?;
+                rest = after.trim();
+            }
+        }
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        let (word, args) = match rest.split_once(char::is_whitespace) {
+            Some((word, args)) => (word, args.trim()),
+            None => (rest, ""),
+        };
+        match word.to_ascii_lowercase().as_str() {
+            ".org" => self.org(line_no, args),
+            ".byte" | ".db" => self.data(line_no, args, 1),
+            ".word" | ".dw" => self.data(line_no, args, 2),
+            directive if directive.starts_with('.') => Err(format!("unknown directive {}", word)),
+            _ => self.instruction(line_no, &word.to_ascii_uppercase(), args),
+        }
+    }
+
+    fn define(&mut self, name: &str, value: i64) -> Result<(), String> {
+        let previous = self.symbols.insert(name.to_string(), value);
+        if self.pass == Some(Pass::Layout) && previous.is_some() {
+            return Err(format!("{} is defined twice", name));
+        }
+        Ok(())
+    }
+
+    fn org(&mut self, line_no: usize, args: &str) -> Result<(), String> {
+        let addr = self
+            .eval(line_no, args)?
+            .ok_or(".org must not use labels defined further down")?;
+        let addr = to_u16(addr)?;
+        match self.origin {
+            None if self.out.is_empty() => self.origin = Some(addr),
+            _ if (addr as u32) < self.pc => {
+                return Err(format!(".org ${:04X} is behind the current address", addr));
+            }
+            _ => self.out.resize(self.out.len() + (addr as u32 - self.pc) as usize, 0),
+        }
+        self.pc = addr as u32;
+        Ok(())
+    }
+
+    fn data(&mut self, line_no: usize, args: &str, width: usize) -> Result<(), String> {
+        for item in split_args(args) {
+            if let Some(text) = item.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+                if width != 1 {
+                    return Err("strings are only allowed in .byte".to_string());
+                }
+                self.emit(text.as_bytes())?;
+                continue;
+            }
+            let value = self.eval(line_no, item)?.unwrap_or(0);
+            if width == 1 {
+                self.emit(&[to_u8(value)?])?;
+            } else {
+                self.emit(&to_u16(value)?.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, line_no: usize, mnemonic: &str, args: &str) -> Result<(), String> {
+        if !opcode::instructions(self.variant).any(|op| op.mnemonic == mnemonic) {
+            return Err(format!("unknown instruction {}", mnemonic));
+        }
+        let operand = parse_operand(args);
+
+        if is_branch(mnemonic) {
+            let expr = match &operand {
+                Operand::Direct(expr) => expr,
+                _ => return Err(format!("{} takes a branch target", mnemonic)),
+            };
+            let op = find(self.variant, mnemonic, |op| op.len == 2)?;
+            let target = self.eval(line_no, expr)?.unwrap_or(self.pc as i64 + 2);
+            let offset = target - (self.pc as i64 + 2);
+            if !(-128..=127).contains(&offset) {
+                return Err(format!("branch target is {} bytes away", offset));
+            }
+            return self.emit(&[op.code, offset as u8]);
+        }
+
+        let (op, value) = match operand {
+            Operand::Implied | Operand::Accumulator => (find(self.variant, mnemonic, |op| op.len == 1)?, None),
+            Operand::Immediate(expr) => (
+                find(self.variant, mnemonic, |op| op.mode == AddressingMode::Immediate)?,
+                self.eval(line_no, &expr)?,
+            ),
+            Operand::Indirect(expr) if mnemonic == "JMP" => {
+                (find(self.variant, mnemonic, |op| op.code == 0x6c)?, self.eval(line_no, &expr)?)
+            }
+            Operand::Indirect(expr) => (
+                find(self.variant, mnemonic, |op| op.mode == AddressingMode::ZeroPage_Indirect)
+                    .map_err(|_| format!("{} has no indirect mode", mnemonic))?,
+                self.eval(line_no, &expr)?,
+            ),
+            Operand::IndirectX(expr) if mnemonic == "JMP" => {
+                (find(self.variant, mnemonic, |op| op.code == 0x7c)?, self.eval(line_no, &expr)?)
+            }
+            Operand::IndirectX(expr) => (
+                find(self.variant, mnemonic, |op| op.mode == AddressingMode::Indirect_X)?,
+                self.eval(line_no, &expr)?,
+            ),
+            Operand::IndirectY(expr) => (
+                find(self.variant, mnemonic, |op| op.mode == AddressingMode::Indirect_Y)?,
+                self.eval(line_no, &expr)?,
+            ),
+            Operand::Direct(expr) if mnemonic == "JMP" || mnemonic == "JSR" => {
+                (find(self.variant, mnemonic, |op| op.code == 0x4c || op.code == 0x20)?, self.eval(line_no, &expr)?)
+            }
+            Operand::Direct(expr) => self.direct(line_no, mnemonic, &expr, AddressingMode::ZeroPage, AddressingMode::Absolute)?,
+            Operand::DirectX(expr) => self.direct(line_no, mnemonic, &expr, AddressingMode::ZeroPage_X, AddressingMode::Absolute_X)?,
+            Operand::DirectY(expr) => self.direct(line_no, mnemonic, &expr, AddressingMode::ZeroPage_Y, AddressingMode::Absolute_Y)?,
+        };
+
+        let value = value.unwrap_or(0);
+        match op.len {
+            1 => self.emit(&[op.code]),
+            2 => self.emit(&[op.code, to_u8(value)?]),
+            _ => {
+                let [lo, hi] = to_u16(value)?.to_le_bytes();
+                self.emit(&[op.code, lo, hi])
+            }
+        }
+    }
+
+    /// Picks zero page or absolute addressing for `expr`.
+    fn direct(
+        &mut self,
+        line_no: usize,
+        mnemonic: &str,
+        expr: &str,
+        zero_page: AddressingMode,
+        absolute: AddressingMode,
+    ) -> Result<(&'static OpCode, Option<i64>), String> {
+        let value = self.eval(line_no, expr)?;
+        let fits = !self.forward_refs.contains(&line_no) && value.is_some_and(|v| (0..=0xff).contains(&v));
+        let op = match find(self.variant, mnemonic, |op| op.mode == zero_page) {
+            Ok(op) if fits => op,
+            _ => find(self.variant, mnemonic, |op| op.mode == absolute)?,
+        };
+        Ok((op, value))
+    }
+
+    /// Evaluates an expression; `None` while a label is not defined yet in the layout pass.
+    fn eval(&mut self, line_no: usize, expr: &str) -> Result<Option<i64>, String> {
+        let tokens = tokenize(expr)?;
+        let forward = self.pass == Some(Pass::Layout)
+            && tokens
+                .iter()
+                .any(|token| matches!(token, Token::Symbol(name) if self.forward_constants.contains(name)));
+        let mut parser = ExprParser {
+            tokens,
+            pos: 0,
+            symbols: &self.symbols,
+            pc: self.line_pc as i64,
+            undefined: None,
+        };
+        let value = parser.parse()?;
+        match parser.undefined {
+            None if !forward => Ok(Some(value)),
+            Some(name) if self.pass == Some(Pass::Emit) => Err(format!("undefined label {}", name)),
+            _ => {
+                self.forward_refs.insert(line_no);
+                Ok(None)
+            }
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if self.pc + bytes.len() as u32 > 0x10000 {
+            return Err("program runs past $FFFF".to_string());
+        }
+        if self.origin.is_none() {
+            self.origin = Some(self.pc as u16);
+        }
+        self.out.extend_from_slice(bytes);
+        self.pc += bytes.len() as u32;
+        Ok(())
+    }
+}
+
+fn find(variant: CpuVariant, mnemonic: &str, matches: impl Fn(&OpCode) -> bool) -> Result<&'static OpCode, String> {
+    opcode::instructions(variant)
+        .find(|op| op.mnemonic == mnemonic && matches(op))
+        .ok_or(format!("addressing mode not available for {}", mnemonic))
+}
+
+fn is_branch(mnemonic: &str) -> bool {
+    matches!(mnemonic, "BCC" | "BCS" | "BEQ" | "BMI" | "BNE" | "BPL" | "BVC" | "BVS" | "BRA")
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Splits a directive's arguments on commas outside of string literals.
+fn split_args(args: &str) -> Vec<&str> {
+    let mut items = vec![];
+    let mut quoted = false;
+    let mut begin = 0;
+    for (i, c) in args.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(args[begin..i].trim());
+                begin = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(args[begin..].trim());
+    items.retain(|item| !item.is_empty());
+    items
+}
+
+fn parse_operand(args: &str) -> Operand {
+    let args = args.trim();
+    let upper = args.to_ascii_uppercase().replace(' ', "");
+    let before_comma = || args[..args.rfind(',').unwrap()].trim().to_string();
+
+    if args.is_empty() {
+        Operand::Implied
+    } else if upper == "A" {
+        Operand::Accumulator
+    } else if let Some(expr) = args.strip_prefix('#') {
+        Operand::Immediate(expr.trim().to_string())
+    } else if upper.starts_with('(') && upper.ends_with(",X)") {
+        Operand::IndirectX(before_comma()[1..].trim().to_string())
+    } else if upper.starts_with('(') && upper.ends_with("),Y") && balanced(&upper[..upper.len() - 2]) {
+        let inner = args[..args.rfind(')').unwrap()].trim();
+        Operand::IndirectY(inner[1..].trim().to_string())
+    } else if upper.starts_with('(') && upper.ends_with(')') && balanced(&upper) {
+        Operand::Indirect(args[1..args.len() - 1].trim().to_string())
+    } else if upper.ends_with(",X") {
+        Operand::DirectX(before_comma())
+    } else if upper.ends_with(",Y") {
+        Operand::DirectY(before_comma())
+    } else {
+        Operand::Direct(args.to_string())
+    }
+}
+
+/// True when the parentheses opened by the first character close at the last one,
+/// so `(a)` is indirect but `(a)+(b)` is an expression.
+fn balanced(text: &str) -> bool {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 && i != text.len() - 1 {
+                    return false;
+                }
+            }
+            _ => {}
+        }
+    }
+    depth == 0
+}
+
+fn to_u8(value: i64) -> Result<u8, String> {
+    match value {
+        -0x80..=0xff => Ok(value as u8),
+        _ => Err(format!("{} does not fit in a byte", value)),
+    }
+}
+
+fn to_u16(value: i64) -> Result<u16, String> {
+    match value {
+        -0x8000..=0xffff => Ok(value as u16),
+        _ => Err(format!("{} does not fit in a word", value)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 13] = ["<<", ">>", "+", "-", "*", "/", "&", "|", "^", "<", ">", "(", ")"];
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        // (token, characters consumed)
+        let (token, len) = if let Some(hex) = rest.strip_prefix('$') {
+            let (value, len) = number(hex, 16)?;
+            (Token::Number(value), len + 1)
+        } else if let Some(bin) = rest.strip_prefix('%') {
+            let (value, len) = number(bin, 2)?;
+            (Token::Number(value), len + 1)
+        } else if c.is_ascii_digit() {
+            let (value, len) = number(rest, 10)?;
+            (Token::Number(value), len)
+        } else if c == '\'' {
+            let mut chars = rest[1..].chars();
+            match (chars.next(), chars.next()) {
+                (Some(ch), Some('\'')) => (Token::Number(ch as i64), ch.len_utf8() + 2),
+                _ => return Err(format!("bad character literal in {}", expr)),
+            }
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (Token::Symbol(rest[..len].to_string()), len)
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            (Token::Op(op), op.len())
+        } else {
+            return Err(format!("unexpected {:?} in {}", c, expr));
+        };
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Parses the digits at the start of `text`, returning the value and the number of digits.
+fn number(text: &str, radix: u32) -> Result<(i64, usize), String> {
+    let len = text.find(|c: char| !c.is_digit(radix)).unwrap_or(text.len());
+    let value = i64::from_str_radix(&text[..len], radix).map_err(|_| format!("bad number {}", text))?;
+    Ok((value, len))
+}
+
+struct ExprParser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: &'a HashMap<String, i64>,
+    pc: i64,
+    /// first label used before its definition
+    undefined: Option<String>,
+}
+
+/// Binary operators from the loosest to the tightest binding.
+const PRECEDENCE: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/"]];
+
+impl<'a> ExprParser<'a> {
+    fn parse(&mut self) -> Result<i64, String> {
+        if self.tokens.is_empty() {
+            return Err("missing operand".to_string());
+        }
+        let value = self.binary(0)?;
+        match self.tokens.get(self.pos) {
+            None => Ok(value),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut value = self.binary(level + 1)?;
+        while let Some(Token::Op(op)) = self.tokens.get(self.pos) {
+            let op = *op;
+            if !PRECEDENCE[level].contains(&op) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            value = match op {
+                "|" => value | rhs,
+                "^" => value ^ rhs,
+                "&" => value & rhs,
+                "<<" => value << (rhs & 63),
+                ">>" => value >> (rhs & 63),
+                "+" => value + rhs,
+                "-" => value - rhs,
+                "*" => value * rhs,
+                _ if rhs == 0 => return Err("division by zero".to_string()),
+                _ => value / rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        let token = self.tokens.get(self.pos).cloned().ok_or("expression ends early")?;
+        self.pos += 1;
+        match token {
+            Token::Number(value) => Ok(value),
+            Token::Symbol(name) => match self.symbols.get(&name) {
+                Some(value) => Ok(*value),
+                None => {
+                    self.undefined.get_or_insert(name);
+                    Ok(0)
+                }
+            },
+            Token::Op("-") => Ok(-self.unary()?),
+            Token::Op("<") => Ok(self.unary()? & 0xff),
+            Token::Op(">") => Ok(self.unary()? >> 8 & 0xff),
+            Token::Op("*") => Ok(self.pc),
+            Token::Op("(") => {
+                let value = self.binary(0)?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Op(")")) => {
+                        self.pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err("missing )".to_string()),
+                }
+            }
+            token => Err(format!("unexpected {:?}", token)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::disasm;
+
+    #[test]
+    fn test_addressing_modes() {
+        let program = assemble(
+            "
+            LDA #$10
+            LDA $10
+            LDA $10,X
+            LDX $10,Y
+            LDA $1234
+            LDA $1234,X
+            LDA $10,Y       ; no zero page,Y for LDA
+            LDA ($20,X)
+            LDA ($20),Y
+            ASL A
+            ASL
+            JMP ($0200)
+            JSR $0600
+            CLC
+            ",
+            CpuVariant::Ricoh2A03,
+        )
+        .unwrap();
+
+        assert_eq!(
+            program,
+            vec![
+                0xa9, 0x10, 0xa5, 0x10, 0xb5, 0x10, 0xb6, 0x10, 0xad, 0x34, 0x12, 0xbd, 0x34, 0x12,
+                0xb9, 0x10, 0x00, 0xa1, 0x20, 0xb1, 0x20, 0x0a, 0x0a, 0x6c, 0x00, 0x02, 0x20, 0x00,
+                0x06, 0x18,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_directives_and_expressions() {
+        let program = assemble(
+            "
+            count = 2 + 3 * 2       ; 8
+            start:  ldx #count
+            loop:   dex
+                    bne loop
+                    lda data+1
+                    ldy #>vector
+                    jmp end
+            data:   .byte 1, %10, 'a', \"b;c\"
+            vector: .word start, * + 2
+                    .org $0620
+            end:    brk
+            ",
+            CpuVariant::Ricoh2A03,
+        )
+        .unwrap();
+
+        assert_eq!(
+            &program[..20],
+            &[
+                0xa2, 0x08, 0xca, 0xd0, 0xfd, 0xad, 0x0e, 0x06, 0xa0, 0x06, 0x4c, 0x20, 0x06,
+                0x01, 0x02, 0x61, 0x62, 0x3b, 0x63, 0x00,
+            ]
+        );
+        assert_eq!(&program[20..24], &[0x06, 0x15, 0x06, 0x00]);
+        assert_eq!(program.len(), 0x21);
+        assert_eq!(program[0x20], 0x00);
+
+        let program = assemble(
+            "
+            ptr = data
+            far = ptr + 1
+                    lda ptr
+                    lda far,x
+                    jmp done
+            done:   brk
+            data:   .byte 1
+            ",
+            CpuVariant::Ricoh2A03,
+        )
+        .unwrap();
+        assert_eq!(program, [0xad, 0x0a, 0x06, 0xbd, 0x0b, 0x06, 0x4c, 0x09, 0x06, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_runs_on_cpu() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(
+            assemble(
+                "
+                    LDX #0
+                    LDA #0
+                loop:
+                    CLC
+                    ADC #3
+                    INX
+                    CPX #5
+                    BNE loop
+                    STA result
+                    BRK
+                result = $10
+                ",
+                CpuVariant::Ricoh2A03,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(cpu.register_a, 15);
+        assert_eq!(cpu.memory[0x10], 15);
+    }
+
+    #[test]
+    fn test_reassemble_disassembly() {
+        let program = vec![
+            0xa2, 0x08, 0xca, 0x95, 0x10, 0xd0, 0xfb, 0x20, 0x0e, 0x06, 0x6c, 0x00, 0x02, 0x00,
+            0x11, 0x20, 0x3e, 0x00, 0x03, 0x60,
+        ];
+        let mut cpu = CPU::new();
+        cpu.load(program.clone()).unwrap();
+
+        let mut source = String::new();
+        let mut addr = DEFAULT_ORIGIN;
+        while addr < DEFAULT_ORIGIN + program.len() as u16 {
+            let instruction = disasm::decode(CpuVariant::Ricoh2A03, &cpu, addr);
+            let operand = instruction.operand(&Default::default());
+            source.push_str(&format!("{} {}\n", instruction.mnemonic(), operand));
+            addr += instruction.bytes.len() as u16;
+        }
+
+        assert_eq!(assemble(&source, CpuVariant::Ricoh2A03).unwrap(), program);
+    }
+
+    #[test]
+    fn test_65c02_instructions() {
+        let source = "
+            loop:   BRA skip
+                    BIT #$80
+                    BIT $10,X
+                    STZ $1234,X
+                    LDA ($20)
+                    INC A
+            skip:   JMP ($0700,X)
+            ";
+        assert_eq!(
+            assemble(source, CpuVariant::Cmos65C02).unwrap(),
+            vec![
+                0x80, 0x0a, 0x89, 0x80, 0x34, 0x10, 0x9e, 0x34, 0x12, 0xb2, 0x20, 0x1a, 0x7c, 0x00,
+                0x07,
+            ]
+        );
+        assert_eq!(
+            assemble(source, CpuVariant::Nmos6502).unwrap_err(),
+            "line 2: unknown instruction BRA"
+        );
+        assert!(assemble("LDA ($20)", CpuVariant::Ricoh2A03).is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(assemble("LDA #1\nFOO $10", CpuVariant::Ricoh2A03).unwrap_err(), "line 2: unknown instruction FOO");
+        assert_eq!(assemble("JMP nowhere", CpuVariant::Ricoh2A03).unwrap_err(), "line 1: undefined label nowhere");
+        assert!(assemble("x: NOP\nx: NOP", CpuVariant::Ricoh2A03).is_err());
+        assert!(assemble("STX $1234,X", CpuVariant::Ricoh2A03).is_err());
+        assert!(assemble("LDA #$100", CpuVariant::Ricoh2A03).is_err());
+        assert!(assemble(".org $0700\nNOP\n.org $0600", CpuVariant::Ricoh2A03).is_err());
+        assert!(assemble("loop: .byte 0\n .org * + 200\n BNE loop", CpuVariant::Ricoh2A03).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::error::EmuError;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const PRG_ROM_PAGE_SIZE: usize = 16384;
+const CHR_ROM_PAGE_SIZE: usize = 8192;
+
+pub const PRG_RAM_START: u16 = 0x6000;
+pub const PRG_RAM_SIZE: usize = 0x2000;
+
+#[derive(Debug, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum Mirroring {
+    VERTICAL,
+    HORIZONTAL,
+    FOUR_SCREEN,
+}
+
+pub struct Rom {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub mapper: u8,
+    pub screen_mirroring: Mirroring,
+    pub battery: bool,
+}
+
+impl Rom {
+    /// Parses an iNES 1.0 image. https://wiki.nesdev.com/w/index.php/INES
+    pub fn new(raw: &[u8]) -> Result<Rom, EmuError> {
+        if raw.len() < 16 || raw[0..4] != NES_TAG {
+            return Err(EmuError::InvalidRom("File is not in iNES file format".to_string()));
+        }
+
+        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+
+        let ines_ver = (raw[7] >> 2) & 0b11;
+        if ines_ver != 0 {
+            return Err(EmuError::InvalidRom("NES2.0 format is not supported".to_string()));
+        }
+
+        let four_screen = raw[6] & 0b1000 != 0;
+        let vertical_mirroring = raw[6] & 0b1 != 0;
+        let screen_mirroring = match (four_screen, vertical_mirroring) {
+            (true, _) => Mirroring::FOUR_SCREEN,
+            (false, true) => Mirroring::VERTICAL,
+            (false, false) => Mirroring::HORIZONTAL,
+        };
+
+        let battery = raw[6] & 0b10 != 0;
+
+        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+
+        let skip_trainer = raw[6] & 0b100 != 0;
+
+        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
+        let chr_rom_start = prg_rom_start + prg_rom_size;
+
+        if raw.len() < chr_rom_start + chr_rom_size {
+            return Err(EmuError::InvalidRom("File is truncated".to_string()));
+        }
+
+        Ok(Rom {
+            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
+            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+            mapper,
+            screen_mirroring,
+            battery,
+        })
+    }
+}
+
+/// Battery-backed PRG-RAM ($6000-$7FFF) mirrored to a `.sav` file next to the ROM.
+///
+/// Only NROM-style cartridges are supported for now, so there is no mapper
+/// EEPROM to persist besides the PRG-RAM window.
+pub struct BatteryRam {
+    path: PathBuf,
+    last_flushed: Vec<u8>,
+}
+
+impl BatteryRam {
+    pub fn new(rom_path: &Path) -> Self {
+        BatteryRam {
+            path: rom_path.with_extension("sav"),
+            last_flushed: vec![0; PRG_RAM_SIZE],
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Copies the `.sav` file into PRG-RAM. A missing file leaves the RAM untouched.
+    pub fn load(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        for (i, byte) in data.iter().take(PRG_RAM_SIZE).enumerate() {
+            cpu.mem_write(PRG_RAM_START + i as u16, *byte);
+        }
+        self.last_flushed = read_prg_ram(cpu);
+        Ok(())
+    }
+
+    /// Writes PRG-RAM to disk if it changed since the last flush.
+    /// Returns whether the file was written.
+    pub fn flush(&mut self, cpu: &CPU) -> io::Result<bool> {
+        let ram = read_prg_ram(cpu);
+        if ram == self.last_flushed {
+            return Ok(false);
+        }
+
+        fs::write(&self.path, &ram)?;
+        self.last_flushed = ram;
+        Ok(true)
+    }
+}
+
+fn read_prg_ram(cpu: &CPU) -> Vec<u8> {
+    (0..PRG_RAM_SIZE)
+        .map(|i| cpu.mem_peek(PRG_RAM_START + i as u16))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_rom(flags_6: u8) -> Vec<u8> {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, flags_6, 0x00];
+        raw.resize(16, 0);
+        raw.extend(vec![0xea; PRG_ROM_PAGE_SIZE]);
+        raw.extend(vec![0x02; CHR_ROM_PAGE_SIZE]);
+        raw
+    }
+
+    #[test]
+    fn test_parse_battery_flag() {
+        let rom = Rom::new(&test_rom(0b0000_0011)).unwrap();
+
+        assert!(rom.battery);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_reject_bad_header() {
+        assert!(Rom::new(&[0; 16]).is_err());
+        assert!(Rom::new(&test_rom(0)[..100]).is_err());
+    }
+
+    #[test]
+    fn test_prg_ram_round_trip() {
+        let rom_path = std::env::temp_dir().join("nes_emulator_battery_test.nes");
+        let mut battery = BatteryRam::new(&rom_path);
+        let _ = fs::remove_file(battery.path());
+
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x6000, 0x42);
+        cpu.mem_write(0x7fff, 0x24);
+        assert!(battery.flush(&cpu).unwrap());
+        assert!(!battery.flush(&cpu).unwrap());
+
+        let mut restored = CPU::new();
+        BatteryRam::new(&rom_path).load(&mut restored).unwrap();
+        assert_eq!(restored.mem_read(0x6000), 0x42);
+        assert_eq!(restored.mem_read(0x7fff), 0x24);
+
+        fs::remove_file(battery.path()).unwrap();
+    }
+}
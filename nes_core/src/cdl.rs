@@ -0,0 +1,343 @@
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+use crate::hooks::HookId;
+use crate::hooks::MemoryAccess;
+use crate::opcode::OpCode;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+/// PRG byte flags, as in FCEUX http://fceux.com/web/help/CodeDataLogger.html
+pub const PRG_CODE: u8 = 0x01;
+pub const PRG_DATA: u8 = 0x02;
+/// bits 2-3: which 8KB slot of $8000-$FFFF the byte was last accessed through
+const PRG_BANK_SHIFT: u8 = 2;
+const PRG_BANK_MASK: u8 = 0x03 << PRG_BANK_SHIFT;
+
+/// CHR byte flags
+pub const CHR_RENDERED: u8 = 0x01;
+pub const CHR_READ: u8 = 0x02;
+
+/// Bytes per line of the coverage map in reports.
+const REPORT_BLOCK: usize = 0x1000;
+
+/// Code/Data Logger: records how each PRG-ROM byte of an NROM cartridge was used.
+///
+/// Opcode fetches mark the whole instruction as code, other reads mark data. There
+/// is no PPU yet, so the CHR half of the log stays empty.
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    /// address and length of the instruction being executed; its operand reads
+    /// are part of the code, not data
+    instruction: (u16, u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coverage {
+    pub total: usize,
+    pub code: usize,
+    pub data: usize,
+    /// logged as both code and data
+    pub both: usize,
+}
+
+impl Coverage {
+    pub fn used(&self) -> usize {
+        self.code + self.data - self.both
+    }
+
+    pub fn percent(count: usize, total: usize) -> f64 {
+        if total == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / total as f64
+        }
+    }
+}
+
+impl CodeDataLog {
+    pub fn new(rom: &Rom) -> Self {
+        CodeDataLog {
+            prg: vec![0; rom.prg_rom.len()],
+            chr: vec![0; rom.chr_rom.len()],
+            instruction: (0, 0),
+        }
+    }
+
+    /// Starts logging the CPU's accesses to $8000-$FFFF.
+    pub fn attach(log: &Rc<RefCell<CodeDataLog>>, cpu: &mut CPU) -> HookId {
+        let log = log.clone();
+        let opcodes = cpu.opcodes();
+        cpu.add_memory_hook(
+            MemoryAccess::FETCH | MemoryAccess::READ,
+            0x8000..=0xffff,
+            move |access, addr, data| log.borrow_mut().on_access(opcodes, access, addr, data),
+        )
+    }
+
+    fn on_access(&mut self, opcodes: &HashMap<u8, &'static OpCode>, access: MemoryAccess, addr: u16, data: u8) {
+        if self.prg.is_empty() {
+            return;
+        }
+        if access == MemoryAccess::FETCH {
+            let len = opcodes.get(&data).map_or(1, |op| op.len as u16);
+            self.instruction = (addr, len);
+            for i in 0..len {
+                self.mark(addr.wrapping_add(i), PRG_CODE);
+            }
+            return;
+        }
+
+        let (start, len) = self.instruction;
+        if addr.wrapping_sub(start) < len {
+            return;
+        }
+        self.mark(addr, PRG_DATA);
+    }
+
+    fn mark(&mut self, addr: u16, flag: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        let offset = (addr - 0x8000) as usize % self.prg.len();
+        let bank = ((addr >> 13) & 0x03) as u8;
+        self.prg[offset] = self.prg[offset] & !PRG_BANK_MASK | flag | bank << PRG_BANK_SHIFT;
+    }
+
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
+    pub fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    /// FCEUX `.cdl` layout: one flag byte per PRG byte, then one per CHR byte.
+    pub fn to_cdl(&self) -> Vec<u8> {
+        [&self.prg[..], &self.chr[..]].concat()
+    }
+
+    /// Continues a log saved for the same cartridge.
+    pub fn merge_cdl(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() != self.prg.len() + self.chr.len() {
+            return Err(format!(
+                "log has {} bytes, the ROM needs {}",
+                data.len(),
+                self.prg.len() + self.chr.len()
+            ));
+        }
+        let (prg, chr) = data.split_at(self.prg.len());
+        for (flags, saved) in self.prg.iter_mut().zip(prg).chain(self.chr.iter_mut().zip(chr)) {
+            *flags |= saved;
+        }
+        Ok(())
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_cdl())
+    }
+
+    pub fn prg_coverage(&self) -> Coverage {
+        coverage(&self.prg, 0, self.prg.len())
+    }
+
+    pub fn text_report(&self) -> String {
+        let total = self.prg_coverage();
+        let mut out = format!(
+            "PRG-ROM {} bytes: {} code ({:.1}%), {} data ({:.1}%), {} unused ({:.1}%)\n",
+            total.total,
+            total.code,
+            Coverage::percent(total.code, total.total),
+            total.data,
+            Coverage::percent(total.data, total.total),
+            total.total - total.used(),
+            Coverage::percent(total.total - total.used(), total.total),
+        );
+        out.push_str(&format!(
+            "CHR-ROM {} bytes: {} rendered, {} read\n",
+            self.chr.len(),
+            self.chr.iter().filter(|f| *f & CHR_RENDERED != 0).count(),
+            self.chr.iter().filter(|f| *f & CHR_READ != 0).count(),
+        ));
+
+        out.push_str("\noffset  code    data    used\n");
+        for start in (0..self.prg.len()).step_by(REPORT_BLOCK) {
+            let block = coverage(&self.prg, start, REPORT_BLOCK);
+            out.push_str(&format!(
+                "{:05X}  {:5.1}%  {:5.1}%  {:5.1}%\n",
+                start,
+                Coverage::percent(block.code, block.total),
+                Coverage::percent(block.data, block.total),
+                Coverage::percent(block.used(), block.total),
+            ));
+        }
+        out
+    }
+
+    /// One cell per 256 bytes, shaded by how much of it was used.
+    pub fn html_report(&self, title: &str) -> String {
+        let total = self.prg_coverage();
+        let mut out = String::new();
+        out.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\n");
+        out.push_str(&format!("<title>Coverage of {}</title>\n", escape(title)));
+        out.push_str(
+            "<style>body{font-family:monospace} td.page{width:12px;height:12px;padding:0}\
+             table.map{border-spacing:1px}</style>\n</head><body>\n",
+        );
+        out.push_str(&format!("<h1>Coverage of {}</h1>\n", escape(title)));
+        out.push_str(&format!(
+            "<p>PRG-ROM {} bytes: {} code ({:.1}%), {} data ({:.1}%), {} used ({:.1}%)</p>\n",
+            total.total,
+            total.code,
+            Coverage::percent(total.code, total.total),
+            total.data,
+            Coverage::percent(total.data, total.total),
+            total.used(),
+            Coverage::percent(total.used(), total.total),
+        ));
+        out.push_str("<p>Each cell is 256 bytes: blue code, green data, teal both, grey unused.</p>\n");
+
+        out.push_str("<table class=\"map\">\n");
+        for start in (0..self.prg.len()).step_by(REPORT_BLOCK) {
+            out.push_str(&format!("<tr><td>{:05X}</td>", start));
+            for page in (start..(start + REPORT_BLOCK).min(self.prg.len())).step_by(0x100) {
+                let cell = coverage(&self.prg, page, 0x100);
+                let shade = |count: usize| 64 + count * 191 / cell.total.max(1);
+                let color = if cell.used() == 0 {
+                    "#ddd".to_string()
+                } else {
+                    format!("rgb(0,{},{})", shade(cell.data), shade(cell.code))
+                };
+                out.push_str(&format!(
+                    "<td class=\"page\" style=\"background:{}\" title=\"{:05X}: {} code, {} data\"></td>",
+                    color, page, cell.code, cell.data
+                ));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n</body></html>\n");
+        out
+    }
+}
+
+fn coverage(flags: &[u8], start: usize, len: usize) -> Coverage {
+    let block = &flags[start.min(flags.len())..(start + len).min(flags.len())];
+    Coverage {
+        total: block.len(),
+        code: block.iter().filter(|f| *f & PRG_CODE != 0).count(),
+        data: block.iter().filter(|f| *f & PRG_DATA != 0).count(),
+        both: block.iter().filter(|f| *f & (PRG_CODE | PRG_DATA) == PRG_CODE | PRG_DATA).count(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::cartridge::Mirroring;
+    use crate::cpu::CpuVariant;
+
+    fn rom(prg_rom: Vec<u8>) -> Rom {
+        Rom {
+            prg_rom,
+            chr_rom: vec![0; 0x2000],
+            mapper: 0,
+            screen_mirroring: Mirroring::HORIZONTAL,
+            battery: false,
+        }
+    }
+
+    #[test]
+    fn test_log_code_and_data() {
+        let mut program = assemble(
+            "
+                .org $c000
+            reset:
+                LDA table
+                LDX #1
+                LDA table,X
+                BRK
+            table:
+                .byte 5, 6, 7
+            ",
+            CpuVariant::Ricoh2A03,
+        )
+        .unwrap();
+        program.resize(0x4000, 0);
+        program[0x3ffc..0x3ffe].copy_from_slice(&[0x00, 0xc0]);
+        let rom = rom(program);
+
+        let mut cpu = CPU::new();
+        cpu.load_rom(&rom).unwrap();
+        let log = Rc::new(RefCell::new(CodeDataLog::new(&rom)));
+        CodeDataLog::attach(&log, &mut cpu);
+        cpu.reset();
+        cpu.run().unwrap();
+
+        let log = log.borrow();
+        // accessed through $C000-$DFFF: bank bits 10
+        let code = PRG_CODE | 2 << PRG_BANK_SHIFT;
+        let data = PRG_DATA | 2 << PRG_BANK_SHIFT;
+        assert_eq!(&log.prg()[..12], &[code, code, code, code, code, code, code, code, code, data, data, 0]);
+        assert_eq!(log.prg()[0x3ffc], PRG_DATA | 3 << PRG_BANK_SHIFT);
+        assert_eq!(log.to_cdl().len(), 0x6000);
+
+        let coverage = log.prg_coverage();
+        assert_eq!((coverage.code, coverage.data, coverage.both), (9, 4, 0));
+        assert!(log.text_report().starts_with("PRG-ROM 16384 bytes: 9 code (0.1%), 4 data (0.0%)"));
+        assert!(log.html_report("<test>").contains("Coverage of &lt;test&gt;"));
+    }
+
+    #[test]
+    fn test_mirrored_bytes_keep_the_last_bank() {
+        let mut program = assemble(
+            "
+                .org $c000
+                LDA $e010
+                LDA $a010
+                LDA $c020
+                LDA $8020
+                BRK
+            ",
+            CpuVariant::Ricoh2A03,
+        )
+        .unwrap();
+        program.resize(0x4000, 0);
+        program[0x3ffc..0x3ffe].copy_from_slice(&[0x00, 0xc0]);
+        let rom = rom(program);
+
+        let mut cpu = CPU::new();
+        cpu.load_rom(&rom).unwrap();
+        let log = Rc::new(RefCell::new(CodeDataLog::new(&rom)));
+        CodeDataLog::attach(&log, &mut cpu);
+        cpu.reset();
+        cpu.run().unwrap();
+
+        let log = log.borrow();
+        // not bank 11, the bits of both accesses
+        assert_eq!(log.prg()[0x2010], PRG_DATA | 1 << PRG_BANK_SHIFT);
+        assert_eq!(log.prg()[0x0020], PRG_DATA);
+    }
+
+    #[test]
+    fn test_merge_saved_log() {
+        let rom = rom(vec![0; 0x4000]);
+        let mut log = CodeDataLog::new(&rom);
+        let mut saved = vec![0; 0x6000];
+        saved[1] = PRG_CODE;
+        saved[0x4000] = CHR_RENDERED;
+        log.merge_cdl(&saved).unwrap();
+
+        assert_eq!(log.prg()[1], PRG_CODE);
+        assert_eq!(log.chr()[0], CHR_RENDERED);
+        assert!(log.merge_cdl(&saved[1..]).is_err());
+    }
+}
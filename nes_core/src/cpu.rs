@@ -0,0 +1,2104 @@
+use crate::cartridge::Rom;
+use crate::error::EmuError;
+use crate::hooks::HookId;
+use crate::hooks::MemoryAccess;
+use crate::hooks::MemoryHooks;
+use crate::opcode;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+bitflags! {
+    /// # Status Register (P) http://wiki.nesdev.com/w/index.php/Status_flags
+    ///
+    ///  7 6 5 4 3 2 1 0
+    ///  N V _ B D I Z C
+    ///  | |   | | | | +--- Carry Flag
+    ///  | |   | | | +----- Zero Flag
+    ///  | |   | | +------- Interrupt Disable
+    ///  | |   | +--------- Decimal Mode (not used on NES)
+    ///  | |   +----------- Break Command
+    ///  | +--------------- Overflow Flag
+    ///  +----------------- Negative Flag
+    ///
+    pub struct CpuFlags: u8 {
+        const CARRY             = 0b00000001;
+        const ZERO              = 0b00000010;
+        const INTERRUPT_DISABLE = 0b00000100;
+        const DECIMAL_MODE      = 0b00001000;
+        const BREAK             = 0b00010000;
+        const BREAK2            = 0b00100000;
+        const OVERFLOW          = 0b01000000;
+        const NEGATIV           = 0b10000000;
+    }
+}
+
+const STACK: u16 = 0x0100;
+const STACK_RESET: u8 = 0xfd;
+const PROGRAM_START: u16 = 0x0600;
+
+/// KIL (also JAM or HLT): the CPU locks up until it is reset.
+/// https://www.nesdev.org/wiki/CPU_unofficial_opcodes
+const JAM_OPCODES: [u8; 12] = [0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xb2, 0xd2, 0xf2];
+
+fn page_cross(addr1: u16, addr2: u16) -> bool {
+    addr1 & 0xFF00 != addr2 & 0xFF00
+}
+
+/// Member of the 6502 family to emulate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde-savestates", derive(serde::Serialize, serde::Deserialize))]
+pub enum CpuVariant {
+    /// Ricoh 2A03 of the NES: an NMOS 6502 with its decimal mode cut out.
+    #[default]
+    Ricoh2A03,
+    /// NMOS 6502, whose BCD arithmetic leaves N, V and Z meaningless.
+    Nmos6502,
+    /// 65C02: BCD sets N and Z from the result and costs an extra cycle.
+    Cmos65C02,
+}
+
+impl CpuVariant {
+    pub fn has_decimal_mode(&self) -> bool {
+        *self != CpuVariant::Ricoh2A03
+    }
+}
+
+/// Why the CPU stopped executing instructions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde-savestates", derive(serde::Serialize, serde::Deserialize))]
+pub enum HaltReason {
+    /// BRK at `pc`; stepping again continues after it.
+    Break { pc: u16 },
+    /// A KIL opcode at `pc` locked up the CPU until the next reset.
+    Jam { opcode: u8, pc: u16 },
+    /// The jump or branch at `pc` targets itself, so nothing can change any more.
+    /// Only reported when `detect_infinite_loops` is on.
+    InfiniteLoop { pc: u16 },
+}
+
+impl fmt::Display for HaltReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HaltReason::Break { pc } => write!(f, "BRK at ${:04X}", pc),
+            HaltReason::Jam { opcode, pc } => write!(f, "CPU jammed by opcode ${:02X} at ${:04X}", opcode, pc),
+            HaltReason::InfiniteLoop { pc } => write!(f, "infinite loop at ${:04X}", pc),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde-savestates", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum Interrupt {
+    Nmi,
+    Irq,
+    Brk,
+}
+
+pub struct CPU {
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub status: CpuFlags,
+    pub program_counter: u16,
+    pub stack_pointer: u8,
+    pub cycles: usize,
+    /// Halt when a JMP or branch jumps to itself while no interrupt is pending. Only for
+    /// programs nothing interrupts later: NES games wait for NMI in such loops.
+    /// Programs waiting on a changing memory location are not caught.
+    pub detect_infinite_loops: bool,
+    /// Stop at BRK (`HaltReason::Break`), as test programs expect, instead of taking the
+    /// software interrupt through $FFFE.
+    pub break_halts: bool,
+    /// Issue the bus access of every cycle in the order of the NMOS 6502, including the
+    /// dummy reads and writes whose data is discarded. Hooks see those as
+    /// `MemoryAccess::DUMMY`. Off, each instruction only does the accesses it needs.
+    pub cycle_accurate_bus: bool,
+    pub(crate) halt: Option<HaltReason>,
+    /// cycle of the NMI edge that is yet to be serviced
+    pub(crate) nmi_edge: Option<usize>,
+    /// cycle since which the IRQ line is asserted
+    pub(crate) irq_line: Option<usize>,
+    /// what the last instruction's poll found, taken before the next instruction
+    pub(crate) pending_interrupt: Option<Interrupt>,
+    variant: CpuVariant,
+    opcodes: &'static HashMap<u8, &'static opcode::OpCode>,
+    dispatch: &'static DispatchTable,
+    pub(crate) memory: [u8; 0x10000],
+    /// in a cell so reads through `&self` can notify them
+    hooks: RefCell<MemoryHooks>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum AddressingMode {
+    Immediate,
+    ZeroPage,
+    ZeroPage_X,
+    ZeroPage_Y,
+    Absolute,
+    Absolute_X,
+    Absolute_Y,
+    Indirect_X,
+    Indirect_Y,
+    /// `($nn)`, 65C02 only
+    ZeroPage_Indirect,
+    NoneAddressing,
+}
+
+pub trait Mem {
+    fn mem_read(&self, addr: u16) -> u8;
+
+    fn mem_write(&mut self, addr: u16, data: u8);
+
+    /// Reads without side effects, for debuggers and tracers; memory hooks do not see it.
+    fn mem_peek(&self, addr: u16) -> u8 {
+        self.mem_read(addr)
+    }
+
+    fn mem_peek_u16(&self, pos: u16) -> u16 {
+        let lo = self.mem_peek(pos) as u16;
+        let hi = self.mem_peek(pos.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
+    fn mem_read_u16(&self, pos: u16) -> u16 {
+        let lo = self.mem_read(pos) as u16;
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
+    fn mem_write_u16(&mut self, pos: u16, data: u16) {
+        let hi = (data >> 8) as u8;
+        let lo = (data & 0xff) as u8;
+        self.mem_write(pos, lo);
+        self.mem_write(pos.wrapping_add(1), hi);
+    }
+}
+
+impl Mem for CPU {
+    fn mem_read(&self, addr: u16) -> u8 {
+        let data = self.memory[addr as usize];
+        self.notify(MemoryAccess::READ, addr, data);
+        data
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.memory[addr as usize] = data;
+        self.notify(MemoryAccess::WRITE, addr, data);
+    }
+
+    fn mem_peek(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CPU {
+    pub fn new() -> Self {
+        Self::with_variant(CpuVariant::Ricoh2A03)
+    }
+
+    pub fn with_variant(variant: CpuVariant) -> Self {
+        CPU {
+            register_a: 0,
+            register_x: 0,
+            register_y: 0,
+            stack_pointer: STACK_RESET,
+            program_counter: 0,
+            status: CpuFlags::from_bits_truncate(0b100100),
+            cycles: 0,
+            detect_infinite_loops: false,
+            break_halts: true,
+            cycle_accurate_bus: false,
+            halt: None,
+            nmi_edge: None,
+            irq_line: None,
+            pending_interrupt: None,
+            variant,
+            opcodes: opcode::opcodes_map(variant),
+            dispatch: dispatch_table(variant),
+            memory: [0; 0x10000],
+            hooks: RefCell::new(MemoryHooks::default()),
+        }
+    }
+
+    pub fn variant(&self) -> CpuVariant {
+        self.variant
+    }
+
+    /// The instruction set of this CPU's variant.
+    pub fn opcodes(&self) -> &'static HashMap<u8, &'static opcode::OpCode> {
+        self.opcodes
+    }
+
+    /// Returns the operand address and whether indexing crossed a page boundary,
+    /// which costs read instructions an extra cycle.
+    fn get_operand_address(&self, mode: &AddressingMode) -> Result<(u16, bool), EmuError> {
+        let operand = match mode {
+            AddressingMode::Immediate => (self.program_counter, false),
+
+            AddressingMode::ZeroPage => (self.mem_read(self.program_counter) as u16, false),
+
+            AddressingMode::Absolute => (self.mem_read_u16(self.program_counter), false),
+
+            AddressingMode::ZeroPage_X => {
+                let pos = self.mem_read(self.program_counter);
+                self.dummy_read(pos as u16);
+                (pos.wrapping_add(self.register_x) as u16, false)
+            }
+            AddressingMode::ZeroPage_Y => {
+                let pos = self.mem_read(self.program_counter);
+                self.dummy_read(pos as u16);
+                (pos.wrapping_add(self.register_y) as u16, false)
+            }
+
+            AddressingMode::Absolute_X => {
+                let base = self.mem_read_u16(self.program_counter);
+                let addr = base.wrapping_add(self.register_x as u16);
+                (addr, page_cross(base, addr))
+            }
+            AddressingMode::Absolute_Y => {
+                let base = self.mem_read_u16(self.program_counter);
+                let addr = base.wrapping_add(self.register_y as u16);
+                (addr, page_cross(base, addr))
+            }
+
+            AddressingMode::Indirect_X => {
+                let base = self.mem_read(self.program_counter);
+                self.dummy_read(base as u16);
+
+                let ptr: u8 = base.wrapping_add(self.register_x);
+                let lo = self.mem_read(ptr as u16);
+                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
+                ((hi as u16) << 8 | (lo as u16), false)
+            }
+            AddressingMode::Indirect_Y => {
+                let base = self.mem_read(self.program_counter);
+
+                let lo = self.mem_read(base as u16);
+                let hi = self.mem_read(base.wrapping_add(1) as u16);
+                let deref_base = (hi as u16) << 8 | (lo as u16);
+                let deref = deref_base.wrapping_add(self.register_y as u16);
+                (deref, page_cross(deref_base, deref))
+            }
+            AddressingMode::ZeroPage_Indirect => {
+                let base = self.mem_read(self.program_counter);
+
+                let lo = self.mem_read(base as u16);
+                let hi = self.mem_read(base.wrapping_add(1) as u16);
+                ((hi as u16) << 8 | (lo as u16), false)
+            }
+
+            AddressingMode::NoneAddressing => {
+                return Err(EmuError::UnsupportedAddressingMode {
+                    mode: *mode,
+                    pc: self.program_counter.wrapping_sub(1),
+                });
+            }
+        };
+
+        // indexing is done on the low byte first; when that carries, the address
+        // without the carry is read before the right one
+        let (addr, page_cross) = operand;
+        if page_cross {
+            self.dummy_read(addr.wrapping_sub(0x100));
+        }
+        Ok(operand)
+    }
+
+    /// `get_operand_address` for instructions that write. Indexed modes always take
+    /// the extra cycle and its dummy read, page crossed or not.
+    fn get_write_address(&self, mode: &AddressingMode) -> Result<u16, EmuError> {
+        let (addr, page_cross) = self.get_operand_address(mode)?;
+        let indexed = matches!(
+            mode,
+            AddressingMode::Absolute_X | AddressingMode::Absolute_Y | AddressingMode::Indirect_Y
+        );
+        if indexed && !page_cross {
+            self.dummy_read(addr);
+        }
+        Ok(addr)
+    }
+
+    fn ldy(&mut self, mode: &AddressingMode) -> Result<(), EmuError> {
+        let (addr, page_cross) = self.get_operand_address(mode)?;
+        let data = self.mem_read(addr);
+        self.register_y = data;
+        self.update_zero_and_negative_flags(self.register_y);
+        if page_cross {
+            self.cycles += 1;
+        }
+        Ok(())
+    }
+
+    fn ldx(&mut self, mode: &AddressingMode) -> Result<(), EmuError> {
+        let (addr, page_cross) = self.get_operand_address(mode)?;
+        let data = self.mem_read(addr);
+        self.register_x = data;
+        self.update_zero_and_negative_flags(self.register_x);
+        if page_cross {
+            self.cycles += 1;
+        }
+        Ok(())
+    }
+
+    fn lda(&mut self, mode: &AddressingMode) -> Result<(), EmuError> {
+        let (addr, page_cross) = self.get_operand_address(mode)?;
+        let value = self.mem_read(addr);
+        self.set_register_a(value);
+        if page_cross {
+            self.cycles += 1;
+        }
+        Ok(())
+    }
+
+    fn sta(&mut self, mode: &AddressingMode) -> Result<(), EmuError> {
+        let addr = self.get_write_address(mode)?;
+        self.mem_write(addr, self.register_a);
+        Ok(())
+    }
+
+    fn set_register_a(&mut self, value: u8) {
+        self.register_a = value;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    fn and(&mut self, mode: &AddressingMode) -> Result<(), EmuError> {
+        let (addr, page_cross) = self.get_operand_address(mode)?;
+        let data = self.mem_read(addr);
+        self.set_register_a(data & self.register_a);
+        if page_cross {
+            self.cycles += 1;
+        }
+        Ok(())
+    }
+
+    fn eor(&mut self, mode: &AddressingMode) -> Result<(), EmuError> {
+        let (addr, page_cross) = self.get_operand_address(mode)?;
+        let data = self.mem_read(addr);
+        self.set_register_a(data ^ self.register_a);
+        if page_cross {
+            self.cycles += 1;
+        }
+        Ok(())
+    }
+
+    fn ora(&mut self, mode: &AddressingMode) -> Result<(), EmuError> {
+        let (addr, page_cross) = self.get_operand_address(mode)?;
+        let data = self.mem_read(addr);
+        self.set_register_a(data | self.register_a);
+        if page_cross {
+            self.cycles += 1;
+        }
+        Ok(())
+    }
+
+    fn tax(&mut self) {
+        self.register_x = self.register_a;
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    fn update_zero_and_negative_flags(&mut self, result: u8) {
+        if result == 0 {
+            self.status.insert(CpuFlags::ZERO);
+        } else {
+            self.status.remove(CpuFlags::ZERO);
+        }
+
+        if result >> 7 == 1 {
+            self.status.insert(CpuFlags::NEGATIV);
+        } else {
+            self.status.remove(CpuFlags::NEGATIV);
+        }
+    }
+
+    fn update_negative_flags(&mut self, result: u8) {
+        if result >> 7 == 1 {
+            self.status.insert(CpuFlags::NEGATIV)
+        } else {
+            self.status.remove(CpuFlags::NEGATIV)
+        }
+    }
+
+    fn inx(&mut self) {
+        self.register_x = self.register_x.wrapping_add(1);
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    fn iny(&mut self) {
+        self.register_y = self.register_y.wrapping_add(1);
+        self.update_zero_and_negative_flags(self.register_y);
+    }
+
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> Result<HaltReason, EmuError> {
+        self.load(program)?;
+        self.program_counter = self.mem_read_u16(0xFFFC);
+        self.run()
+    }
+
+    /// Calls `callback` for every `access` to an address in `range`, after the access.
+    pub fn add_memory_hook<F>(&mut self, access: MemoryAccess, range: RangeInclusive<u16>, callback: F) -> HookId
+    where
+        F: FnMut(MemoryAccess, u16, u8) + 'static,
+    {
+        self.hooks.get_mut().add(access, range, Box::new(callback))
+    }
+
+    pub fn remove_memory_hook(&mut self, id: HookId) -> bool {
+        self.hooks.get_mut().remove(id)
+    }
+
+    fn notify(&self, access: MemoryAccess, addr: u16, data: u8) {
+        let mut hooks = self.hooks.borrow_mut();
+        if !hooks.is_empty() {
+            hooks.fire(access, addr, data);
+        }
+    }
+
+    /// A read the hardware does although the CPU ignores the data, only issued when
+    /// `cycle_accurate_bus` is on.
+    fn dummy_read(&self, addr: u16) {
+        if self.cycle_accurate_bus {
+            self.notify(MemoryAccess::READ | MemoryAccess::DUMMY, addr, self.memory[addr as usize]);
+        }
+    }
+
+    /// Read-modify-write instructions write the unmodified value back first.
+    fn dummy_write(&mut self, addr: u16, data: u8) {
+        if self.cycle_accurate_bus {
+            self.memory[addr as usize] = data;
+            self.notify(MemoryAccess::WRITE | MemoryAccess::DUMMY, addr, data);
+        }
+    }
+
+    /// Pulls spend a cycle reading the stack before the stack pointer moves.
+    fn dummy_stack_read(&self) {
+        self.dummy_read(STACK + self.stack_pointer as u16);
+    }
+
+    fn fetch(&self, addr: u16) -> u8 {
+        let code = self.memory[addr as usize];
+        self.notify(MemoryAccess::FETCH, addr, code);
+        code
+    }
+
+    /// Copies `program` to $0600 and points the reset vector at it. The program has to
+    /// end below the vector at $FFFC.
+    pub fn load(&mut self, program: Vec<u8>) -> Result<(), EmuError> {
+        if PROGRAM_START as usize + program.len() > 0xFFFC {
+            return Err(EmuError::LoadOutOfRange { start: PROGRAM_START, len: program.len() });
+        }
+        let start = PROGRAM_START as usize;
+        self.memory[start..(start + program.len())].copy_from_slice(&program[..]);
+        self.mem_write_u16(0xFFFC, PROGRAM_START);
+        Ok(())
+    }
+
+    /// Maps an NROM cartridge: PRG-ROM at $8000, a single 16KB bank is mirrored at $C000.
+    pub fn load_rom(&mut self, rom: &Rom) -> Result<(), EmuError> {
+        if rom.mapper != 0 {
+            return Err(EmuError::UnsupportedMapper(rom.mapper));
+        }
+        if rom.prg_rom.is_empty() {
+            return Err(EmuError::InvalidRom("no PRG-ROM".to_string()));
+        }
+        for (i, byte) in self.memory[0x8000..].iter_mut().enumerate() {
+            *byte = rom.prg_rom[i % rom.prg_rom.len()];
+        }
+        Ok(())
+    }
+
+    pub fn reset(&mut self) {
+        self.register_a = 0;
+        self.register_x = 0;
+        self.register_y = 0;
+        self.stack_pointer = STACK_RESET;
+        self.status = CpuFlags::from_bits_truncate(0b100100);
+        // self.memory = [0; 0xFFFF];
+        self.halt = None;
+        self.nmi_edge = None;
+        self.pending_interrupt = None;
+
+        self.program_counter = self.mem_read_u16(0xFFFC);
+        self.cycles += 7;
+    }
+
+    fn set_carry_flag(&mut self) {
+        self.status.insert(CpuFlags::CARRY)
+    }
+
+    fn clear_carry_flag(&mut self) {
+        self.status.remove(CpuFlags::CARRY)
+    }
+
+    /// Binary addition, also the only mode of the 2A03.
+    /// http://www.righto.com/2012/12/the-6502-overflow-flag-explained.html
+    fn add_to_register_a(&mut self, data: u8) {
+        let sum = self.register_a as u16
+            + data as u16
+            + (if self.status.contains(CpuFlags::CARRY) {
+                1
+            } else {
+                0
+            }) as u16;
+
+        let carry = sum > 0xff;
+
+        if carry {
+            self.status.insert(CpuFlags::CARRY);
+        } else {
+            self.status.remove(CpuFlags::CARRY);
+        }
+
+        let result = sum as u8;
+
+        if (data ^ result) & (result ^ self.register_a) & 0x80 != 0 {
+            self.status.insert(CpuFlags::OVERFLOW);
+        } else {
+            self.status.remove(CpuFlags::OVERFLOW)
+        }
+
+        self.set_register_a(result);
+    }
+
+    fn decimal_mode(&self) -> bool {
+        self.variant.has_decimal_mode() && self.status.contains(CpuFlags::DECIMAL_MODE)
+    }
+
+    /// BCD addition; invalid BCD operands give the same results as the hardware.
+    /// http://www.6502.org/tutorials/decimal_mode.html#A
+    fn add_decimal_to_register_a(&mut self, data: u8) {
+        let a = self.register_a as i16;
+        let b = data as i16;
+        let carry = self.status.contains(CpuFlags::CARRY) as i16;
+
+        let mut low = (a & 0x0f) + (b & 0x0f) + carry;
+        if low >= 0x0a {
+            low = ((low + 0x06) & 0x0f) + 0x10;
+        }
+        // N and V see the high digits as signed, before they are adjusted
+        let signed = (a & 0xf0) as u8 as i8 as i16 + (b & 0xf0) as u8 as i8 as i16 + low;
+        let mut sum = (a & 0xf0) + (b & 0xf0) + low;
+        if sum >= 0xa0 {
+            sum += 0x60;
+        }
+
+        self.status.set(CpuFlags::CARRY, sum >= 0x100);
+        self.status.set(CpuFlags::OVERFLOW, !(-128..=127).contains(&signed));
+        self.register_a = sum as u8;
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.update_zero_and_negative_flags(self.register_a);
+            self.cycles += 1;
+        } else {
+            self.status.set(CpuFlags::ZERO, (a + b + carry) as u8 == 0);
+            self.status.set(CpuFlags::NEGATIV, signed & 0x80 != 0);
+        }
+    }
+
+    /// BCD subtraction. C and V are those of the binary subtraction, so are N and Z on
+    /// the NMOS 6502. http://www.6502.org/tutorials/decimal_mode.html#A
+    fn subtract_decimal_from_register_a(&mut self, data: u8) {
+        let a = self.register_a as i16;
+        let b = data as i16;
+        let borrow = !self.status.contains(CpuFlags::CARRY) as i16;
+        self.add_to_register_a(!data);
+
+        let low = (a & 0x0f) - (b & 0x0f) - borrow;
+        let difference = if self.variant == CpuVariant::Cmos65C02 {
+            let mut difference = a - b - borrow;
+            if difference < 0 {
+                difference -= 0x60;
+            }
+            if low < 0 {
+                difference -= 0x06;
+            }
+            difference
+        } else {
+            let low = if low < 0 { ((low - 0x06) & 0x0f) - 0x10 } else { low };
+            let difference = (a & 0xf0) - (b & 0xf0) + low;
+            if difference < 0 {
+                difference - 0x60
+            } else {
+                difference
+            }
+        };
+
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.set_register_a(difference as u8);
+            self.cycles += 1;
+        } else {
+            self.register_a = difference as u8;
+        }
+    }
+
+    fn sbc(&mut self, mode: &AddressingMode) -> Result<(), EmuError> {
+        let (addr, page_cross) = self.get_operand_address(mode)?;
+        let data = self.mem_read(addr);
+        if self.decimal_mode() {
+            self.subtract_decimal_from_register_a(data);
+        } else {
+            self.add_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+        }
+        if page_cross {
+            self.cycles += 1;
+        }
+        Ok(())
+    }
+
+    fn adc(&mut self, mode: &AddressingMode) -> Result<(), EmuError> {
+        let (addr, page_cross) = self.get_operand_address(mode)?;
+        let value = self.mem_read(addr);
+        if self.decimal_mode() {
+            self.add_decimal_to_register_a(value);
+        } else {
+            self.add_to_register_a(value);
+        }
+        if page_cross {
+            self.cycles += 1;
+        }
+        Ok(())
+    }
+
+    fn stack_pop(&mut self) -> u8 {
+        self.stack_pointer = self.stack_pointer.wrapping_add(1);
+        self.mem_read(STACK + self.stack_pointer as u16)
+    }
+
+    fn stack_push(&mut self, data: u8) {
+        self.mem_write(STACK + self.stack_pointer as u16, data);
+        self.stack_pointer = self.stack_pointer.wrapping_sub(1)
+    }
+
+    fn stack_push_u16(&mut self, data: u16) {
+        let hi = (data >> 8) as u8;
+        let lo = (data & 0xff) as u8;
+        self.stack_push(hi);
+        self.stack_push(lo);
+    }
+
+    fn stack_pop_u16(&mut self) -> u16 {
+        let lo = self.stack_pop() as u16;
+        let hi = self.stack_pop() as u16;
+
+        hi << 8 | lo
+    }
+
+    fn asl_accumulator(&mut self) {
+        let mut data = self.register_a;
+        if data >> 7 == 1 {
+            self.set_carry_flag();
+        } else {
+            self.clear_carry_flag();
+        }
+        data <<= 1;
+        self.set_register_a(data)
+    }
+
+    fn asl(&mut self, mode: &AddressingMode) -> Result<u8, EmuError> {
+        let addr = self.get_write_address(mode)?;
+        let mut data = self.mem_read(addr);
+        self.dummy_write(addr, data);
+        if data >> 7 == 1 {
+            self.set_carry_flag();
+        } else {
+            self.clear_carry_flag();
+        }
+        data <<= 1;
+        self.mem_write(addr, data);
+        self.update_zero_and_negative_flags(data);
+        Ok(data)
+    }
+
+    fn lsr_accumulator(&mut self) {
+        let mut data = self.register_a;
+        if data & 1 == 1 {
+            self.set_carry_flag();
+        } else {
+            self.clear_carry_flag();
+        }
+        data >>= 1;
+        self.set_register_a(data)
+    }
+
+    fn lsr(&mut self, mode: &AddressingMode) -> Result<u8, EmuError> {
+        let addr = self.get_write_address(mode)?;
+        let mut data = self.mem_read(addr);
+        self.dummy_write(addr, data);
+        if data & 1 == 1 {
+            self.set_carry_flag();
+        } else {
+            self.clear_carry_flag();
+        }
+        data >>= 1;
+        self.mem_write(addr, data);
+        self.update_zero_and_negative_flags(data);
+        Ok(data)
+    }
+
+    fn rol(&mut self, mode: &AddressingMode) -> Result<u8, EmuError> {
+        let addr = self.get_write_address(mode)?;
+        let mut data = self.mem_read(addr);
+        self.dummy_write(addr, data);
+        let old_carry = self.status.contains(CpuFlags::CARRY);
+
+        if data >> 7 == 1 {
+            self.set_carry_flag();
+        } else {
+            self.clear_carry_flag();
+        }
+        data <<= 1;
+        if old_carry {
+            data |= 1;
+        }
+        self.mem_write(addr, data);
+        self.update_negative_flags(data);
+        Ok(data)
+    }
+
+    fn rol_accumulator(&mut self) {
+        let mut data = self.register_a;
+        let old_carry = self.status.contains(CpuFlags::CARRY);
+
+        if data >> 7 == 1 {
+            self.set_carry_flag();
+        } else {
+            self.clear_carry_flag();
+        }
+        data <<= 1;
+        if old_carry {
+            data |= 1;
+        }
+        self.set_register_a(data);
+    }
+
+    fn ror(&mut self, mode: &AddressingMode) -> Result<u8, EmuError> {
+        let addr = self.get_write_address(mode)?;
+        let mut data = self.mem_read(addr);
+        self.dummy_write(addr, data);
+        let old_carry = self.status.contains(CpuFlags::CARRY);
+
+        if data & 1 == 1 {
+            self.set_carry_flag();
+        } else {
+            self.clear_carry_flag();
+        }
+        data >>= 1;
+        if old_carry {
+            data |= 0b10000000;
+        }
+        self.mem_write(addr, data);
+        self.update_negative_flags(data);
+        Ok(data)
+    }
+
+    fn ror_accumulator(&mut self) {
+        let mut data = self.register_a;
+        let old_carry = self.status.contains(CpuFlags::CARRY);
+
+        if data & 1 == 1 {
+            self.set_carry_flag();
+        } else {
+            self.clear_carry_flag();
+        }
+        data >>= 1;
+        if old_carry {
+            data |= 0b10000000;
+        }
+        self.set_register_a(data);
+    }
+
+    fn inc(&mut self, mode: &AddressingMode) -> Result<u8, EmuError> {
+        let addr = self.get_write_address(mode)?;
+        let mut data = self.mem_read(addr);
+        self.dummy_write(addr, data);
+        data = data.wrapping_add(1);
+        self.mem_write(addr, data);
+        self.update_zero_and_negative_flags(data);
+        Ok(data)
+    }
+
+    fn dey(&mut self) {
+        self.register_y = self.register_y.wrapping_sub(1);
+        self.update_zero_and_negative_flags(self.register_y);
+    }
+
+    fn dex(&mut self) {
+        self.register_x = self.register_x.wrapping_sub(1);
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    fn dec(&mut self, mode: &AddressingMode) -> Result<u8, EmuError> {
+        let addr = self.get_write_address(mode)?;
+        let mut data = self.mem_read(addr);
+        self.dummy_write(addr, data);
+        data = data.wrapping_sub(1);
+        self.mem_write(addr, data);
+        self.update_zero_and_negative_flags(data);
+        Ok(data)
+    }
+
+    fn pla(&mut self) {
+        self.dummy_stack_read();
+        let data = self.stack_pop();
+        self.set_register_a(data);
+    }
+
+    fn plp(&mut self) {
+        self.dummy_stack_read();
+        self.status.bits = self.stack_pop();
+        self.status.remove(CpuFlags::BREAK);
+        self.status.insert(CpuFlags::BREAK2);
+    }
+
+    fn php(&mut self) {
+        //http://wiki.nesdev.com/w/index.php/CPU_status_flag_behavior
+        let mut flags = self.status;
+        flags.insert(CpuFlags::BREAK);
+        flags.insert(CpuFlags::BREAK2);
+        self.stack_push(flags.bits());
+    }
+
+    fn bit(&mut self, mode: &AddressingMode) -> Result<(), EmuError> {
+        let (addr, page_cross) = self.get_operand_address(mode)?;
+        let data = self.mem_read(addr);
+        let and = self.register_a & data;
+        if and == 0 {
+            self.status.insert(CpuFlags::ZERO);
+        } else {
+            self.status.remove(CpuFlags::ZERO);
+        }
+        if page_cross {
+            self.cycles += 1;
+        }
+
+        // the 65C02's BIT #imm only sets Z
+        if *mode != AddressingMode::Immediate {
+            self.status.set(CpuFlags::NEGATIV, data & 0b10000000 > 0);
+            self.status.set(CpuFlags::OVERFLOW, data & 0b01000000 > 0);
+        }
+        Ok(())
+    }
+
+    /// TSB and TRB: Z tells whether A and memory share a bit, then A's bits are set
+    /// in or cleared from memory.
+    fn test_bits(&mut self, mode: &AddressingMode, set: bool) -> Result<(), EmuError> {
+        let addr = self.get_write_address(mode)?;
+        let data = self.mem_read(addr);
+        self.dummy_write(addr, data);
+        self.status.set(CpuFlags::ZERO, data & self.register_a == 0);
+        let data = if set { data | self.register_a } else { data & !self.register_a };
+        self.mem_write(addr, data);
+        Ok(())
+    }
+
+    fn compare(&mut self, mode: &AddressingMode, compare_with: u8) -> Result<(), EmuError> {
+        let (addr, page_cross) = self.get_operand_address(mode)?;
+        let data = self.mem_read(addr);
+        if page_cross {
+            self.cycles += 1;
+        }
+        if data <= compare_with {
+            self.status.insert(CpuFlags::CARRY);
+        } else {
+            self.status.remove(CpuFlags::CARRY);
+        }
+
+        self.update_zero_and_negative_flags(compare_with.wrapping_sub(data));
+        Ok(())
+    }
+
+    fn branch(&mut self, condition: bool) {
+        let jump: i8 = self.mem_read(self.program_counter) as i8;
+        if condition {
+            self.cycles += 1;
+
+            let next = self.program_counter.wrapping_add(1);
+            let jump_addr = next.wrapping_add(jump as u16);
+            self.dummy_read(next);
+
+            if page_cross(next, jump_addr) {
+                self.cycles += 1;
+                self.dummy_read(next & 0xff00 | jump_addr & 0x00ff);
+            }
+
+            self.program_counter = jump_addr;
+        }
+    }
+
+    pub fn run(&mut self) -> Result<HaltReason, EmuError> {
+        self.run_with_callback(|_| {})
+    }
+
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<HaltReason, EmuError>
+    where
+        F: FnMut(&mut CPU),
+    {
+        while self.step()? {
+            callback(self);
+        }
+        Ok(self.halt.expect("step returns false only when halted"))
+    }
+
+    /// Signals an NMI edge now, between two instructions.
+    pub fn raise_nmi(&mut self) {
+        self.raise_nmi_at(self.cycles);
+    }
+
+    /// Signals an NMI edge that happened at `cycle`, which can be inside the last
+    /// instruction or ahead of `cycles`: interrupts are polled before the last cycle of
+    /// an instruction, so one raised later is only taken after the next instruction.
+    pub fn raise_nmi_at(&mut self, cycle: usize) {
+        if self.nmi_edge.is_none() {
+            self.nmi_edge = Some(cycle);
+        }
+    }
+
+    /// Asserts or releases the IRQ line now. It is level triggered: the device keeps it
+    /// asserted until its interrupt was acknowledged.
+    pub fn set_irq(&mut self, asserted: bool) {
+        self.set_irq_at(asserted, self.cycles);
+    }
+
+    /// Like `set_irq`, with the cycle the line changed at, see `raise_nmi_at`.
+    pub fn set_irq_at(&mut self, asserted: bool, cycle: usize) {
+        match (asserted, self.irq_line) {
+            (true, None) => self.irq_line = Some(cycle),
+            (false, _) => self.irq_line = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    /// Polls the interrupt lines as the CPU does at `boundary`, the start of an
+    /// instruction's last cycle, with the I flag as it was at that point.
+    fn poll_interrupts(&mut self, boundary: usize, interrupt_disable: bool) {
+        let seen = |line: Option<usize>| line.is_some_and(|cycle| cycle <= boundary);
+        self.pending_interrupt = if seen(self.nmi_edge) {
+            Some(Interrupt::Nmi)
+        } else if seen(self.irq_line) && !interrupt_disable {
+            Some(Interrupt::Irq)
+        } else {
+            None
+        };
+    }
+
+    /// Pushes the return address and flags and jumps through the vector, 7 cycles from
+    /// `start`. An NMI by the fourth of them hijacks a BRK or IRQ: the NMI vector is
+    /// used, but the pushed B flag still tells a BRK apart.
+    /// https://www.nesdev.org/wiki/CPU_interrupts#Interrupt_hijacking
+    fn interrupt(&mut self, interrupt: Interrupt, start: usize) {
+        self.stack_push_u16(self.program_counter);
+        let mut flags = self.status;
+        flags.set(CpuFlags::BREAK, interrupt == Interrupt::Brk);
+        flags.insert(CpuFlags::BREAK2);
+        self.stack_push(flags.bits());
+        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.status.remove(CpuFlags::DECIMAL_MODE);
+        }
+
+        let nmi = interrupt == Interrupt::Nmi || self.nmi_edge.is_some_and(|cycle| cycle <= start + 4);
+        let vector = if nmi {
+            self.nmi_edge = None;
+            0xFFFA
+        } else {
+            0xFFFE
+        };
+        self.program_counter = self.mem_read_u16(vector);
+    }
+
+    /// Why the last `step` returned `false`.
+    pub fn halt_reason(&self) -> Option<HaltReason> {
+        self.halt
+    }
+
+    /// Executes one instruction, or enters the handler of an interrupt the previous
+    /// instruction polled. Returns `false` when the CPU halted, see `halt_reason`.
+    ///
+    /// An unknown opcode leaves the program counter pointing at it.
+    pub fn step(&mut self) -> Result<bool, EmuError> {
+        if let Some(HaltReason::Jam { .. }) = self.halt {
+            return Ok(false);
+        }
+        self.halt = None;
+
+        let start = self.cycles;
+        if let Some(interrupt) = self.pending_interrupt.take() {
+            // two reads of the next opcode, which is not executed
+            self.dummy_read(self.program_counter);
+            self.dummy_read(self.program_counter);
+            self.interrupt(interrupt, start);
+            self.cycles += 7;
+            // the first instruction of the handler always runs
+            return Ok(true);
+        }
+
+        let interrupt_disable = self.status.contains(CpuFlags::INTERRUPT_DISABLE);
+        let pc = self.program_counter;
+        let code = self.fetch(pc);
+        let Some(instruction) = self.dispatch[code as usize] else {
+            // the 65C02 defines every opcode, so only NMOS ones end up here
+            if JAM_OPCODES.contains(&code) {
+                self.halt = Some(HaltReason::Jam { opcode: code, pc });
+                return Ok(false);
+            }
+            return Err(EmuError::UnknownOpcode { opcode: code, pc });
+        };
+        let opcode = instruction.opcode;
+
+        self.program_counter = self.program_counter.wrapping_add(1);
+        let program_counter_state = self.program_counter;
+        // single byte instructions read the next byte and ignore it, except for the
+        // 65C02's single-cycle NOPs
+        if opcode.len == 1 && opcode.cycles > 1 && code != 0x00 {
+            self.dummy_read(self.program_counter);
+        }
+
+        (instruction.execute)(self, opcode)?;
+        if self.halt.is_some() {
+            return Ok(false);
+        }
+
+        if program_counter_state == self.program_counter {
+            self.program_counter = self.program_counter.wrapping_add((opcode.len - 1) as u16);
+        }
+
+        self.cycles += opcode.cycles as usize;
+
+        // CLI, SEI and PLP change the I flag after the poll, so their effect is delayed
+        // by an instruction. Taken branches that stay in the page skip the poll before
+        // their last cycle; only the one before the operand fetch counts.
+        let interrupt_disable = match code {
+            0x58 | 0x78 | 0x28 => interrupt_disable,
+            _ => self.status.contains(CpuFlags::INTERRUPT_DISABLE),
+        };
+        let branch = code & 0x1f == 0x10 || code == 0x80;
+        let last_cycle = if branch && self.cycles - start == opcode.cycles as usize + 1 {
+            start + 1
+        } else {
+            self.cycles - 1
+        };
+        if code != 0x00 {
+            self.poll_interrupts(last_cycle, interrupt_disable);
+        }
+
+        // JMP absolute, JMP indirect and the branches
+        let jump = code == 0x4c || code == 0x6c || code == 0x7c || branch;
+        if self.detect_infinite_loops && jump && self.program_counter == pc && self.pending_interrupt.is_none() {
+            self.halt = Some(HaltReason::InfiniteLoop { pc });
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+/// Runs an instruction once its opcode is fetched, with the program counter on the
+/// operand. BRK reports a halt through `CPU::halt`.
+type Execute = fn(&mut CPU, &opcode::OpCode) -> Result<(), EmuError>;
+
+/// What `step` needs to run an opcode, looked up by indexing instead of hashing.
+#[derive(Clone, Copy)]
+struct Instruction {
+    opcode: &'static opcode::OpCode,
+    execute: Execute,
+}
+
+type DispatchTable = [Option<Instruction>; 256];
+
+lazy_static! {
+    static ref NMOS_DISPATCH: DispatchTable = build_dispatch_table(&opcode::OPCODES_MAP);
+    static ref CMOS_DISPATCH: DispatchTable = build_dispatch_table(&opcode::CMOS_OPCODES_MAP);
+}
+
+fn dispatch_table(variant: CpuVariant) -> &'static DispatchTable {
+    match variant {
+        CpuVariant::Ricoh2A03 | CpuVariant::Nmos6502 => &NMOS_DISPATCH,
+        CpuVariant::Cmos65C02 => &CMOS_DISPATCH,
+    }
+}
+
+fn build_dispatch_table(opcodes: &HashMap<u8, &'static opcode::OpCode>) -> DispatchTable {
+    let mut table = [None; 256];
+    for (&code, &opcode) in opcodes {
+        table[code as usize] = Some(Instruction { opcode, execute: execute(code) });
+    }
+    table
+}
+
+fn execute(code: u8) -> Execute {
+    match code {
+        0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 | 0xb2 => |cpu, op| cpu.lda(&op.mode),
+
+        0xAA => |cpu, _| {
+            cpu.tax();
+            Ok(())
+        },
+        0xe8 => |cpu, _| {
+            cpu.inx();
+            Ok(())
+        },
+        0x00 => |cpu, _| {
+            let pc = cpu.program_counter.wrapping_sub(1);
+            if cpu.break_halts {
+                cpu.halt = Some(HaltReason::Break { pc });
+                return Ok(());
+            }
+            // skips a padding byte
+            cpu.dummy_read(cpu.program_counter);
+            cpu.program_counter = cpu.program_counter.wrapping_add(1);
+            cpu.interrupt(Interrupt::Brk, cpu.cycles);
+            Ok(())
+        },
+
+        /* CLD */
+        0xd8 => |cpu, _| {
+            cpu.status.remove(CpuFlags::DECIMAL_MODE);
+            Ok(())
+        },
+
+        /* CLI */
+        0x58 => |cpu, _| {
+            cpu.status.remove(CpuFlags::INTERRUPT_DISABLE);
+            Ok(())
+        },
+
+        /* CLV */
+        0xb8 => |cpu, _| {
+            cpu.status.remove(CpuFlags::OVERFLOW);
+            Ok(())
+        },
+
+        /* CLC */
+        0x18 => |cpu, _| {
+            cpu.clear_carry_flag();
+            Ok(())
+        },
+
+        /* SEC */
+        0x38 => |cpu, _| {
+            cpu.set_carry_flag();
+            Ok(())
+        },
+
+        /* SEI */
+        0x78 => |cpu, _| {
+            cpu.status.insert(CpuFlags::INTERRUPT_DISABLE);
+            Ok(())
+        },
+
+        /* SED */
+        0xf8 => |cpu, _| {
+            cpu.status.insert(CpuFlags::DECIMAL_MODE);
+            Ok(())
+        },
+
+        /* PHA */
+        0x48 => |cpu, _| {
+            cpu.stack_push(cpu.register_a);
+            Ok(())
+        },
+
+        /* PLA */
+        0x68 => |cpu, _| {
+            cpu.pla();
+            Ok(())
+        },
+
+        /* PHP */
+        0x08 => |cpu, _| {
+            cpu.php();
+            Ok(())
+        },
+
+        /* PLP */
+        0x28 => |cpu, _| {
+            cpu.plp();
+            Ok(())
+        },
+
+        /* ADC */
+        0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 | 0x72 => |cpu, op| cpu.adc(&op.mode),
+
+        /* SBC */
+        0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 | 0xf2 => |cpu, op| cpu.sbc(&op.mode),
+
+        /* AND */
+        0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 | 0x32 => |cpu, op| cpu.and(&op.mode),
+
+        /* EOR */
+        0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 | 0x52 => |cpu, op| cpu.eor(&op.mode),
+
+        /* ORA */
+        0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 | 0x12 => |cpu, op| cpu.ora(&op.mode),
+
+        /* LSR */
+        0x4a => |cpu, _| {
+            cpu.lsr_accumulator();
+            Ok(())
+        },
+
+        /* LSR */
+        0x46 | 0x56 | 0x4e | 0x5e => |cpu, op| cpu.lsr(&op.mode).map(|_| ()),
+
+        /* ASL */
+        0x0a => |cpu, _| {
+            cpu.asl_accumulator();
+            Ok(())
+        },
+
+        /* ASL */
+        0x06 | 0x16 | 0x0e | 0x1e => |cpu, op| cpu.asl(&op.mode).map(|_| ()),
+
+        /* ROL */
+        0x2a => |cpu, _| {
+            cpu.rol_accumulator();
+            Ok(())
+        },
+
+        /* ROL */
+        0x26 | 0x36 | 0x2e | 0x3e => |cpu, op| cpu.rol(&op.mode).map(|_| ()),
+
+        /* ROR */
+        0x6a => |cpu, _| {
+            cpu.ror_accumulator();
+            Ok(())
+        },
+
+        /* ROR */
+        0x66 | 0x76 | 0x6e | 0x7e => |cpu, op| cpu.ror(&op.mode).map(|_| ()),
+
+        /* INC */
+        0xe6 | 0xf6 | 0xee | 0xfe => |cpu, op| cpu.inc(&op.mode).map(|_| ()),
+
+        /* INY */
+        0xc8 => |cpu, _| {
+            cpu.iny();
+            Ok(())
+        },
+
+        /* DEC */
+        0xc6 | 0xd6 | 0xce | 0xde => |cpu, op| cpu.dec(&op.mode).map(|_| ()),
+
+        /* DEX */
+        0xca => |cpu, _| {
+            cpu.dex();
+            Ok(())
+        },
+
+        /* DEY */
+        0x88 => |cpu, _| {
+            cpu.dey();
+            Ok(())
+        },
+
+        /* CMP */
+        0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 | 0xd2 => |cpu, op| cpu.compare(&op.mode, cpu.register_a),
+
+        /* CPY */
+        0xc0 | 0xc4 | 0xcc => |cpu, op| cpu.compare(&op.mode, cpu.register_y),
+
+        /* CPX */
+        0xe0 | 0xe4 | 0xec => |cpu, op| cpu.compare(&op.mode, cpu.register_x),
+
+        /* JMP Absolute */
+        0x4c => |cpu, _| {
+            let mem_address = cpu.mem_read_u16(cpu.program_counter);
+            cpu.program_counter = mem_address;
+            Ok(())
+        },
+
+        /* JMP Indirect */
+        0x6c => |cpu, _| {
+            let mem_address = cpu.mem_read_u16(cpu.program_counter);
+            // let indirect_ref = self.mem_read_u16(mem_address);
+            //6502 bug mode with with page boundary:
+            //  if address $3000 contains $40, $30FF contains $80, and $3100 contains $50,
+            // the result of JMP ($30FF) will be a transfer of control to $4080 rather than $5080 as you intended
+            // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
+
+            // the 65C02 fixed it, at the cost of a cycle
+            let indirect_ref = if mem_address & 0x00FF == 0x00FF && cpu.variant != CpuVariant::Cmos65C02 {
+                let lo = cpu.mem_read(mem_address);
+                let hi = cpu.mem_read(mem_address & 0xFF00);
+                (hi as u16) << 8 | (lo as u16)
+            } else {
+                cpu.mem_read_u16(mem_address)
+            };
+
+            cpu.program_counter = indirect_ref;
+            Ok(())
+        },
+
+        /* JSR */
+        0x20 => |cpu, _| {
+            // the high byte of the target is read after the return address is pushed
+            let lo = cpu.mem_read(cpu.program_counter);
+            cpu.dummy_stack_read();
+            cpu.stack_push_u16(cpu.program_counter.wrapping_add(1));
+            let hi = cpu.mem_read(cpu.program_counter.wrapping_add(1));
+            cpu.program_counter = (hi as u16) << 8 | lo as u16;
+            Ok(())
+        },
+
+        /* RTS */
+        0x60 => |cpu, _| {
+            cpu.dummy_stack_read();
+            let return_address = cpu.stack_pop_u16();
+            cpu.dummy_read(return_address);
+            cpu.program_counter = return_address.wrapping_add(1);
+            Ok(())
+        },
+
+        /* RTI */
+        0x40 => |cpu, _| {
+            cpu.dummy_stack_read();
+            cpu.status.bits = cpu.stack_pop();
+            cpu.status.remove(CpuFlags::BREAK);
+            cpu.status.insert(CpuFlags::BREAK2);
+
+            cpu.program_counter = cpu.stack_pop_u16();
+            Ok(())
+        },
+
+        /* BNE */
+        0xd0 => |cpu, _| {
+            cpu.branch(!cpu.status.contains(CpuFlags::ZERO));
+            Ok(())
+        },
+
+        /* BVS */
+        0x70 => |cpu, _| {
+            cpu.branch(cpu.status.contains(CpuFlags::OVERFLOW));
+            Ok(())
+        },
+
+        /* BVC */
+        0x50 => |cpu, _| {
+            cpu.branch(!cpu.status.contains(CpuFlags::OVERFLOW));
+            Ok(())
+        },
+
+        /* BPL */
+        0x10 => |cpu, _| {
+            cpu.branch(!cpu.status.contains(CpuFlags::NEGATIV));
+            Ok(())
+        },
+
+        /* BMI */
+        0x30 => |cpu, _| {
+            cpu.branch(cpu.status.contains(CpuFlags::NEGATIV));
+            Ok(())
+        },
+
+        /* BEQ */
+        0xf0 => |cpu, _| {
+            cpu.branch(cpu.status.contains(CpuFlags::ZERO));
+            Ok(())
+        },
+
+        /* BCS */
+        0xb0 => |cpu, _| {
+            cpu.branch(cpu.status.contains(CpuFlags::CARRY));
+            Ok(())
+        },
+
+        /* BCC */
+        0x90 => |cpu, _| {
+            cpu.branch(!cpu.status.contains(CpuFlags::CARRY));
+            Ok(())
+        },
+
+        /* BIT */
+        0x24 | 0x2c | 0x89 | 0x34 | 0x3c => |cpu, op| cpu.bit(&op.mode),
+
+        /* STA */
+        0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 | 0x92 => |cpu, op| cpu.sta(&op.mode),
+
+        /* STX */
+        0x86 | 0x96 | 0x8e => |cpu, op| {
+            let addr = cpu.get_write_address(&op.mode)?;
+            cpu.mem_write(addr, cpu.register_x);
+            Ok(())
+        },
+
+        /* STY */
+        0x84 | 0x94 | 0x8c => |cpu, op| {
+            let addr = cpu.get_write_address(&op.mode)?;
+            cpu.mem_write(addr, cpu.register_y);
+            Ok(())
+        },
+
+        /* JMP Indexed Indirect, (abs,X) */
+        0x7c => |cpu, _| {
+            let mem_address = cpu.mem_read_u16(cpu.program_counter).wrapping_add(cpu.register_x as u16);
+            cpu.program_counter = cpu.mem_read_u16(mem_address);
+            Ok(())
+        },
+
+        /* LDX */
+        0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => |cpu, op| cpu.ldx(&op.mode),
+
+        /* LDY */
+        0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => |cpu, op| cpu.ldy(&op.mode),
+
+        /* BRA */
+        0x80 => |cpu, _| {
+            cpu.branch(true);
+            Ok(())
+        },
+
+        /* PHX */
+        0xda => |cpu, _| {
+            cpu.stack_push(cpu.register_x);
+            Ok(())
+        },
+
+        /* PHY */
+        0x5a => |cpu, _| {
+            cpu.stack_push(cpu.register_y);
+            Ok(())
+        },
+
+        /* PLX */
+        0xfa => |cpu, _| {
+            cpu.dummy_stack_read();
+            cpu.register_x = cpu.stack_pop();
+            cpu.update_zero_and_negative_flags(cpu.register_x);
+            Ok(())
+        },
+
+        /* PLY */
+        0x7a => |cpu, _| {
+            cpu.dummy_stack_read();
+            cpu.register_y = cpu.stack_pop();
+            cpu.update_zero_and_negative_flags(cpu.register_y);
+            Ok(())
+        },
+
+        /* STZ */
+        0x64 | 0x74 | 0x9c | 0x9e => |cpu, op| {
+            let addr = cpu.get_write_address(&op.mode)?;
+            cpu.mem_write(addr, 0);
+            Ok(())
+        },
+
+        /* TRB */
+        0x14 | 0x1c => |cpu, op| cpu.test_bits(&op.mode, false),
+
+        /* TSB */
+        0x04 | 0x0c => |cpu, op| cpu.test_bits(&op.mode, true),
+
+        /* INC A */
+        0x1a => |cpu, _| {
+            cpu.set_register_a(cpu.register_a.wrapping_add(1));
+            Ok(())
+        },
+
+        /* DEC A */
+        0x3a => |cpu, _| {
+            cpu.set_register_a(cpu.register_a.wrapping_sub(1));
+            Ok(())
+        },
+
+        /* NOP */
+        0xea => |_, _| Ok(()),
+
+        /* the 65C02's undefined opcodes */
+        0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xc2 | 0xe2 | 0x44 | 0x54 | 0xd4 | 0xf4 | 0x5c | 0xdc | 0xfc => |_, _| Ok(()),
+        _ if code & 0x03 == 0x03 => |_, _| Ok(()),
+
+        /* TAY */
+        0xa8 => |cpu, _| {
+            cpu.register_y = cpu.register_a;
+            cpu.update_zero_and_negative_flags(cpu.register_y);
+            Ok(())
+        },
+
+        /* TSX */
+        0xba => |cpu, _| {
+            cpu.register_x = cpu.stack_pointer;
+            cpu.update_zero_and_negative_flags(cpu.register_x);
+            Ok(())
+        },
+
+        /* TXA */
+        0x8a => |cpu, _| {
+            cpu.register_a = cpu.register_x;
+            cpu.update_zero_and_negative_flags(cpu.register_a);
+            Ok(())
+        },
+
+        /* TXS */
+        0x9a => |cpu, _| {
+            cpu.stack_pointer = cpu.register_x;
+            Ok(())
+        },
+
+        /* TYA */
+        0x98 => |cpu, _| {
+            cpu.register_a = cpu.register_y;
+            cpu.update_zero_and_negative_flags(cpu.register_a);
+            Ok(())
+        },
+
+        _ => unreachable!("opcode {:02x} is in a table but has no handler", code),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_0xa9_lda_immediate_load_data() {
+        let mut cpu = CPU::new();
+
+        cpu.load_and_run(vec![0xa9, 0x05, 0x00]).unwrap();
+
+        assert_eq!(cpu.register_a, 5);
+        assert!(cpu.status.bits() & 0b0000_0010 == 0b00);
+        assert!(cpu.status.bits() & 0b1000_0000 == 0);
+    }
+
+    #[test]
+    fn test_0xaa_tax_move_a_to_x() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 10;
+
+        cpu.load_and_run(vec![0xaa, 0x00]).unwrap();
+
+        assert_eq!(cpu.register_x, 10)
+    }
+
+    #[test]
+    fn test_5_ops_working_together() {
+        let mut cpu = CPU::new();
+
+        cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]).unwrap();
+
+        assert_eq!(cpu.register_x, 0xc1)
+    }
+
+    #[test]
+    fn test_inx_overflow() {
+        let mut cpu = CPU::new();
+        cpu.register_x = 0xff;
+
+        cpu.load_and_run(vec![0xe8, 0xe8, 0x00]).unwrap();
+
+        assert_eq!(cpu.register_x, 1)
+    }
+
+    #[test]
+    fn test_lda_from_memory() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x10, 0x55);
+
+        cpu.load_and_run(vec![0xa5, 0x10, 0x00]).unwrap();
+
+        assert_eq!(cpu.register_a, 0x55);
+    }
+
+    #[test]
+    fn test_unknown_opcode_error() {
+        let mut cpu = CPU::new();
+
+        let result = cpu.load_and_run(vec![0xe8, 0x03, 0x00]);
+
+        assert_eq!(result, Err(EmuError::UnknownOpcode { opcode: 0x03, pc: 0x0601 }));
+        assert_eq!(cpu.program_counter, 0x0601);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_jam_halts_until_reset() {
+        let mut cpu = CPU::new();
+
+        let reason = cpu.load_and_run(vec![0xe8, 0x02, 0xe8, 0x00]).unwrap();
+
+        assert_eq!(reason, HaltReason::Jam { opcode: 0x02, pc: 0x0601 });
+        assert!(!cpu.step().unwrap());
+        assert_eq!((cpu.program_counter, cpu.register_x), (0x0601, 1));
+
+        cpu.reset();
+        assert_eq!(cpu.halt_reason(), None);
+        assert!(cpu.step().unwrap());
+    }
+
+    #[test]
+    fn test_detect_infinite_loops() {
+        // INX / BNE * / JMP *
+        let program = vec![0xe8, 0xd0, 0xfe, 0x4c, 0x03, 0x06];
+        let mut cpu = CPU::new();
+        cpu.detect_infinite_loops = true;
+        cpu.register_x = 0xff;
+        assert_eq!(cpu.load_and_run(program.clone()).unwrap(), HaltReason::InfiniteLoop { pc: 0x0603 });
+
+        cpu.reset();
+        assert_eq!(cpu.run().unwrap(), HaltReason::InfiniteLoop { pc: 0x0601 });
+
+        let mut cpu = CPU::new();
+        cpu.load(program).unwrap();
+        cpu.reset();
+        for _ in 0..100 {
+            assert!(cpu.step().unwrap());
+        }
+        assert_eq!(cpu.program_counter, 0x0601);
+    }
+
+    /// Runs SED / CLC or SEC / LDA #a / ADC or SBC #b and returns A and the flags.
+    fn decimal(variant: CpuVariant, a: u8, opcode: u8, b: u8, carry: bool) -> (u8, CpuFlags) {
+        let mut cpu = CPU::with_variant(variant);
+        let set_carry = if carry { 0x38 } else { 0x18 };
+        cpu.load_and_run(vec![0xf8, set_carry, 0xa9, a, opcode, b, 0x00]).unwrap();
+        (cpu.register_a, cpu.status)
+    }
+
+    #[test]
+    fn test_decimal_mode() {
+        const ADC: u8 = 0x69;
+        const SBC: u8 = 0xe9;
+        let nmos = CpuVariant::Nmos6502;
+        let flags = |a: (u8, CpuFlags)| {
+            let f = a.1;
+            (a.0, f.contains(CpuFlags::CARRY), f.contains(CpuFlags::ZERO), f.contains(CpuFlags::NEGATIV), f.contains(CpuFlags::OVERFLOW))
+        };
+
+        assert_eq!(decimal(nmos, 0x12, ADC, 0x34, false).0, 0x46);
+        assert_eq!(flags(decimal(nmos, 0x58, ADC, 0x46, true)), (0x05, true, false, true, true));
+        // Z comes from the binary sum $9A, N from the unadjusted high digit
+        assert_eq!(flags(decimal(nmos, 0x99, ADC, 0x01, false)), (0x00, true, false, true, false));
+        assert_eq!(flags(decimal(nmos, 0x79, ADC, 0x00, true)), (0x80, false, false, true, true));
+
+        assert_eq!(flags(decimal(nmos, 0x46, SBC, 0x12, true)), (0x34, true, false, false, false));
+        assert_eq!(flags(decimal(nmos, 0x32, SBC, 0x02, false)), (0x29, true, false, false, false));
+        assert_eq!(flags(decimal(nmos, 0x12, SBC, 0x21, true)), (0x91, false, false, true, false));
+
+        let cmos = CpuVariant::Cmos65C02;
+        assert_eq!(flags(decimal(cmos, 0x99, ADC, 0x01, false)), (0x00, true, true, false, false));
+        assert_eq!(flags(decimal(cmos, 0x12, SBC, 0x21, true)), (0x91, false, false, true, false));
+        assert_eq!(flags(decimal(cmos, 0x40, SBC, 0x40, true)), (0x00, true, true, false, false));
+
+        // the 2A03 ignores the D flag
+        assert_eq!(decimal(CpuVariant::Ricoh2A03, 0x09, ADC, 0x01, false).0, 0x0a);
+        assert_eq!(decimal(CpuVariant::Ricoh2A03, 0x10, SBC, 0x01, true).0, 0x0f);
+    }
+
+    #[test]
+    fn test_65c02_instructions() {
+        let program = vec![
+            0xa2, 0x05, 0xda, 0xa0, 0x07, 0x5a, 0xfa, 0x7a, // LDX #5 / PHX / LDY #7 / PHY / PLX / PLY
+            0xa9, 0x0f, 0x85, 0x10, 0xa9, 0x3c, 0x04, 0x10, // LDA #$0F / STA $10 / LDA #$3C / TSB $10
+            0xa9, 0x03, 0x14, 0x10, 0x64, 0x11, // LDA #$03 / TRB $10 / STZ $11
+            0xa9, 0x00, 0x85, 0x20, 0xa9, 0x02, 0x85, 0x21, // pointer at $20 to $0200
+            0xa9, 0x42, 0x92, 0x20, 0xb2, 0x20, // LDA #$42 / STA ($20) / LDA ($20)
+            0x1a, 0x3a, 0x3a, 0x80, 0x01, 0xe8, 0x00, // INC A / DEC A / DEC A / BRA +1 / INX / BRK
+        ];
+        let mut cpu = CPU::with_variant(CpuVariant::Cmos65C02);
+        cpu.mem_write(0x11, 0xff);
+        cpu.load_and_run(program.clone()).unwrap();
+
+        assert_eq!((cpu.register_a, cpu.register_x, cpu.register_y), (0x41, 7, 5));
+        assert_eq!((cpu.memory[0x10], cpu.memory[0x11], cpu.memory[0x200]), (0x3c, 0, 0x42));
+        assert!(!cpu.status.contains(CpuFlags::ZERO));
+
+        let mut cpu = CPU::new();
+        assert_eq!(cpu.load_and_run(program).unwrap_err(), EmuError::UnknownOpcode { opcode: 0xda, pc: 0x0602 });
+    }
+
+    #[test]
+    fn test_65c02_bit_and_jmp_indexed() {
+        let mut cpu = CPU::with_variant(CpuVariant::Cmos65C02);
+        cpu.load(vec![
+            0xa9, 0x01, 0x89, 0xc0, // LDA #$01 / BIT #$C0
+            0xa2, 0x02, 0x34, 0x0e, // LDX #2 / BIT $0E,X
+            0x3c, 0xff, 0x06, // BIT $06FF,X
+            0x7c, 0x00, 0x07, // JMP ($0700,X)
+        ])
+        .unwrap();
+        cpu.reset();
+        cpu.mem_write(0x10, 0xc0);
+        cpu.mem_write(0x0701, 0x41);
+        cpu.mem_write_u16(0x0702, 0x0620);
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(cpu.status.contains(CpuFlags::ZERO));
+        assert!(!cpu.status.intersects(CpuFlags::NEGATIV | CpuFlags::OVERFLOW));
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(cpu.status.contains(CpuFlags::ZERO | CpuFlags::NEGATIV | CpuFlags::OVERFLOW));
+
+        let cycles = cpu.cycles;
+        cpu.step().unwrap();
+        assert_eq!(cpu.cycles - cycles, 5);
+        assert!(!cpu.status.intersects(CpuFlags::ZERO | CpuFlags::NEGATIV));
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW));
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0620);
+    }
+
+    #[test]
+    fn test_65c02_undefined_opcodes_are_nops() {
+        // NOP #$FF / NOP / NOP $1234 (the 8 cycle one) / NOP $10,X / NOP / BRK
+        let program = vec![0x02, 0xff, 0x03, 0x5c, 0x34, 0x12, 0xf4, 0x10, 0xfb, 0x00];
+        let mut cpu = CPU::with_variant(CpuVariant::Cmos65C02);
+        cpu.load(program.clone()).unwrap();
+        cpu.reset();
+
+        // bytes and cycles of each instruction
+        let mut steps = vec![];
+        loop {
+            let (pc, cycles) = (cpu.program_counter, cpu.cycles);
+            if !cpu.step().unwrap() {
+                break;
+            }
+            steps.push((cpu.program_counter - pc, cpu.cycles - cycles));
+        }
+        assert_eq!(steps, [(2, 2), (1, 1), (3, 8), (2, 4), (1, 1)]);
+        assert_eq!(cpu.halt_reason(), Some(HaltReason::Break { pc: 0x0609 }));
+
+        let mut cpu = CPU::new();
+        assert_eq!(cpu.load_and_run(program).unwrap(), HaltReason::Jam { opcode: 0x02, pc: 0x0600 });
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_wrap() {
+        let jump = |variant| {
+            let mut cpu = CPU::with_variant(variant);
+            cpu.mem_write(0x02ff, 0x10);
+            cpu.mem_write(0x0300, 0x07);
+            cpu.mem_write(0x0200, 0x06);
+            cpu.load_and_run(vec![0x6c, 0xff, 0x02]).unwrap()
+        };
+
+        assert_eq!(jump(CpuVariant::Nmos6502), HaltReason::Break { pc: 0x0610 });
+        assert_eq!(jump(CpuVariant::Cmos65C02), HaltReason::Break { pc: 0x0710 });
+    }
+
+    /// Runs `program` with every bus access recorded, checking that each instruction
+    /// does exactly one access per cycle.
+    fn bus_trace(program: Vec<u8>) -> Vec<Vec<(MemoryAccess, u16, u8)>> {
+        use std::rc::Rc;
+
+        let mut cpu = CPU::new();
+        cpu.cycle_accurate_bus = true;
+        cpu.load(program).unwrap();
+        cpu.reset();
+        let accesses = Rc::new(RefCell::new(vec![]));
+        let log = accesses.clone();
+        cpu.add_memory_hook(MemoryAccess::all(), 0x0000..=0xffff, move |access, addr, data| {
+            log.borrow_mut().push((access, addr, data));
+        });
+
+        let mut instructions = vec![];
+        loop {
+            let (pc, cycles) = (cpu.program_counter, cpu.cycles);
+            if !cpu.step().unwrap() {
+                return instructions;
+            }
+            let accesses = accesses.replace(vec![]);
+            assert_eq!(accesses.len(), cpu.cycles - cycles, "instruction at ${:04X}: {:?}", pc, accesses);
+            instructions.push(accesses);
+        }
+    }
+
+    #[test]
+    fn test_cycle_accurate_bus() {
+        let program = crate::asm::assemble(
+            "
+                LDX #$10
+                LDY #$F0
+                LDA #$01
+                STA $20
+                LDA $20,X
+                LDA $0280,X
+                LDA $02F8,Y
+                STA $0280,X
+                STA $02F8,Y
+                LDA ($20,X)
+                LDA ($40),Y
+                STA ($40),Y
+                INC $20
+                DEC $0280,X
+                ASL A
+                PHA
+                PLA
+                PHP
+                PLP
+                JSR sub
+                CLC
+                BCC skip
+                NOP
+            skip:
+                BCS skip
+                JMP ($0050)
+            sub:
+                RTS
+            ",
+            CpuVariant::Ricoh2A03,
+        )
+        .unwrap();
+        let instructions = bus_trace(program);
+        assert_eq!(instructions.len(), 25);
+
+        let dummy_write = MemoryAccess::WRITE | MemoryAccess::DUMMY;
+        assert_eq!(
+            instructions[12],
+            vec![
+                (MemoryAccess::FETCH, 0x061c, 0xe6),
+                (MemoryAccess::READ, 0x061d, 0x20),
+                (MemoryAccess::READ, 0x0020, 0x01),
+                (dummy_write, 0x0020, 0x01),
+                (MemoryAccess::WRITE, 0x0020, 0x02),
+            ]
+        );
+        // LDA $02F8,Y first reads $02E8, before the carry into the high byte
+        assert_eq!(instructions[6][3], (MemoryAccess::READ | MemoryAccess::DUMMY, 0x02e8, 0));
+
+        // BNE taken across a page
+        let branch = bus_trace(vec![0xd0, 0x80]);
+        let dummy_read = MemoryAccess::READ | MemoryAccess::DUMMY;
+        assert_eq!(branch[0][2..], [(dummy_read, 0x0602, 0), (dummy_read, 0x0682, 0)]);
+    }
+
+    const NMI_HANDLER: u16 = 0x0700;
+    const IRQ_HANDLER: u16 = 0x0780;
+
+    /// A CPU at the start of `program`, with NOP-filled interrupt handlers.
+    fn interrupt_setup(program: Vec<u8>) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load(program).unwrap();
+        cpu.mem_write_u16(0xfffa, NMI_HANDLER);
+        cpu.mem_write_u16(0xfffe, IRQ_HANDLER);
+        for addr in NMI_HANDLER..IRQ_HANDLER + 0x10 {
+            cpu.mem_write(addr, 0xea);
+        }
+        cpu.reset();
+        cpu
+    }
+
+    /// The return address and flags the last interrupt pushed.
+    fn pushed(cpu: &CPU) -> (u16, CpuFlags) {
+        let sp = cpu.stack_pointer as u16;
+        let flags = CpuFlags::from_bits_truncate(cpu.mem_peek(STACK + sp + 1));
+        (cpu.mem_peek_u16(STACK + sp + 2), flags)
+    }
+
+    fn steps(cpu: &mut CPU, count: usize) -> u16 {
+        for _ in 0..count {
+            assert!(cpu.step().unwrap());
+        }
+        cpu.program_counter
+    }
+
+    #[test]
+    fn test_irq_after_cli_sei_plp() {
+        // SEI / CLI / NOP: CLI lets the IRQ in only after the next instruction
+        let mut cpu = interrupt_setup(vec![0x78, 0x58, 0xea, 0xea]);
+        cpu.set_irq(true);
+        assert_eq!(steps(&mut cpu, 3), 0x0603);
+        assert_eq!(steps(&mut cpu, 1), IRQ_HANDLER);
+        let (return_address, flags) = pushed(&cpu);
+        assert_eq!(return_address, 0x0603);
+        assert!(!flags.contains(CpuFlags::BREAK));
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+
+        // CLI / SEI: the IRQ polled by SEI still happens, pushing I set
+        let mut cpu = interrupt_setup(vec![0x58, 0x78, 0xea]);
+        steps(&mut cpu, 1);
+        cpu.set_irq(true);
+        assert_eq!(steps(&mut cpu, 2), IRQ_HANDLER);
+        let (return_address, flags) = pushed(&cpu);
+        assert_eq!(return_address, 0x0602);
+        assert!(flags.contains(CpuFlags::INTERRUPT_DISABLE));
+
+        // LDA #0 / PHA / PLP / NOP
+        let mut cpu = interrupt_setup(vec![0xa9, 0x00, 0x48, 0x28, 0xea, 0xea]);
+        cpu.set_irq(true);
+        assert_eq!(steps(&mut cpu, 4), 0x0605);
+        assert_eq!(steps(&mut cpu, 1), IRQ_HANDLER);
+
+        cpu.set_irq(false);
+        cpu.status.remove(CpuFlags::INTERRUPT_DISABLE);
+        assert_eq!(steps(&mut cpu, 2), IRQ_HANDLER + 2);
+    }
+
+    #[test]
+    fn test_taken_branch_delays_irq() {
+        // an IRQ asserted during the second cycle of a 3 cycle instruction
+        let irq_in_second_cycle = |program: Vec<u8>| {
+            let mut cpu = interrupt_setup(program);
+            cpu.status.remove(CpuFlags::INTERRUPT_DISABLE);
+            steps(&mut cpu, 1);
+            cpu.set_irq_at(true, cpu.cycles + 2);
+            while cpu.program_counter != IRQ_HANDLER {
+                steps(&mut cpu, 1);
+            }
+            pushed(&cpu).0
+        };
+
+        // CLC / BCC +0 / NOP / NOP: polled before the operand fetch only
+        assert_eq!(irq_in_second_cycle(vec![0x18, 0x90, 0x00, 0xea, 0xea]), 0x0604);
+        // CLC / LDA $10 / NOP: polled before the last cycle
+        assert_eq!(irq_in_second_cycle(vec![0x18, 0xa5, 0x10, 0xea, 0xea]), 0x0603);
+    }
+
+    #[test]
+    fn test_nmi_hijacks_irq_and_brk() {
+        let irq_with_nmi_at = |delay: usize| {
+            let mut cpu = interrupt_setup(vec![0xea, 0xea]);
+            cpu.status.remove(CpuFlags::INTERRUPT_DISABLE);
+            cpu.set_irq(true);
+            steps(&mut cpu, 1);
+            cpu.raise_nmi_at(cpu.cycles + delay);
+            steps(&mut cpu, 1);
+            cpu
+        };
+
+        let mut cpu = irq_with_nmi_at(4);
+        assert_eq!(cpu.program_counter, NMI_HANDLER);
+        assert!(!pushed(&cpu).1.contains(CpuFlags::BREAK));
+        assert_eq!(steps(&mut cpu, 2), NMI_HANDLER + 2);
+
+        // too late to hijack: the NMI follows the first instruction of the IRQ handler
+        let mut cpu = irq_with_nmi_at(5);
+        assert_eq!(cpu.program_counter, IRQ_HANDLER);
+        assert_eq!(steps(&mut cpu, 2), NMI_HANDLER);
+        assert_eq!(pushed(&cpu).0, IRQ_HANDLER + 1);
+
+        // BRK / padding
+        let mut cpu = interrupt_setup(vec![0x00, 0xff]);
+        cpu.break_halts = false;
+        cpu.raise_nmi_at(cpu.cycles + 2);
+        assert_eq!(steps(&mut cpu, 1), NMI_HANDLER);
+        let (return_address, flags) = pushed(&cpu);
+        assert_eq!(return_address, 0x0602);
+        assert!(flags.contains(CpuFlags::BREAK));
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let mut cpu = CPU::new();
+        assert_eq!(
+            cpu.load(vec![0xea; 0xfa00]),
+            Err(EmuError::LoadOutOfRange { start: 0x0600, len: 0xfa00 })
+        );
+        assert!(cpu.load(vec![0xea; 0xf9fc]).is_ok());
+
+        let mut rom = Rom {
+            prg_rom: vec![0xea; 0x4000],
+            chr_rom: vec![],
+            mapper: 1,
+            screen_mirroring: crate::cartridge::Mirroring::HORIZONTAL,
+            battery: false,
+        };
+        assert_eq!(cpu.load_rom(&rom), Err(EmuError::UnsupportedMapper(1)));
+        rom.mapper = 0;
+        assert!(cpu.load_rom(&rom).is_ok());
+        rom.prg_rom.clear();
+        assert!(matches!(cpu.load_rom(&rom), Err(EmuError::InvalidRom(_))));
+    }
+
+    #[test]
+    fn test_memory_hooks() {
+        use std::rc::Rc;
+
+        let mut cpu = CPU::new();
+        let seen = Rc::new(RefCell::new(vec![]));
+        let log = seen.clone();
+        let id = cpu.add_memory_hook(MemoryAccess::all(), 0x0010..=0x00ff, move |access, addr, data| {
+            log.borrow_mut().push((access, addr, data));
+        });
+        let fetches = Rc::new(RefCell::new(vec![]));
+        let log = fetches.clone();
+        cpu.add_memory_hook(MemoryAccess::FETCH, 0x0600..=0x0602, move |_, addr, _| log.borrow_mut().push(addr));
+
+        // LDA $10 / STA $11 / STA $0200 / BRK
+        cpu.mem_write(0x10, 0x55);
+        cpu.load_and_run(vec![0xa5, 0x10, 0x85, 0x11, 0x8d, 0x00, 0x02, 0x00]).unwrap();
+
+        assert_eq!(
+            *seen.borrow(),
+            vec![
+                (MemoryAccess::WRITE, 0x10, 0x55),
+                (MemoryAccess::READ, 0x10, 0x55),
+                (MemoryAccess::WRITE, 0x11, 0x55),
+            ]
+        );
+        assert_eq!(*fetches.borrow(), vec![0x0600, 0x0602]);
+
+        assert_eq!(cpu.mem_peek(0x10), 0x55);
+        assert!(cpu.remove_memory_hook(id));
+        assert!(!cpu.remove_memory_hook(id));
+        cpu.mem_write(0x10, 0);
+        assert_eq!(seen.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_program_counter_wraps_at_ffff() {
+        let mut cpu = CPU::new();
+        // NOP at $FFFF, then LDA #$42 split across the wrap
+        cpu.mem_write(0xffff, 0xea);
+        cpu.mem_write(0x0000, 0xa9);
+        cpu.mem_write(0x0001, 0x42);
+        cpu.program_counter = 0xffff;
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0000);
+
+        cpu.mem_write(0xffff, 0xa9);
+        cpu.mem_write(0x0000, 0x42);
+        cpu.program_counter = 0xffff;
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.program_counter, 0x0001);
+    }
+
+    #[test]
+    fn test_u16_access_at_ffff() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(0xffff, 0x1234);
+        assert_eq!(cpu.mem_peek(0xffff), 0x34);
+        assert_eq!(cpu.mem_peek(0x0000), 0x12);
+        assert_eq!(cpu.mem_read_u16(0xffff), 0x1234);
+
+        // LDA $1234 with its operand at $FFFF-$0000
+        cpu.mem_write(0xfffe, 0xad);
+        cpu.mem_write(0x1234, 0x99);
+        cpu.program_counter = 0xfffe;
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_a, 0x99);
+        assert_eq!(cpu.program_counter, 0x0001);
+    }
+
+    #[test]
+    fn test_jsr_and_rts_across_ffff() {
+        let mut cpu = CPU::new();
+        // JSR $0700 at $FFFE, its high byte at $0000
+        cpu.mem_write(0xfffe, 0x20);
+        cpu.mem_write(0xffff, 0x00);
+        cpu.mem_write(0x0000, 0x07);
+        cpu.mem_write(0x0700, 0x60);
+        cpu.program_counter = 0xfffe;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0700);
+        assert_eq!(cpu.mem_peek_u16(STACK + cpu.stack_pointer as u16 + 1), 0x0000);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0001);
+
+        // RTS to $FFFF + 1
+        cpu.stack_push_u16(0xffff);
+        cpu.program_counter = 0x0700;
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0000);
+    }
+}
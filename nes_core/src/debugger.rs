@@ -0,0 +1,616 @@
+use crate::cpu::AddressingMode;
+use crate::cpu::CpuFlags;
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::disasm;
+use crate::disasm::parse_address;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::TryRecvError;
+
+/// Instructions kept for `list` to show what ran before the program counter.
+const HISTORY_LEN: usize = 4;
+
+const HELP: &str = "\
+s, step [n]          execute n instructions (default 1)
+n, next              step over JSR
+f, finish            run until the current subroutine returns
+c, continue          run until a breakpoint or watchpoint
+b, break <addr>      set a breakpoint on PC
+w, watch <addr> [r|w|rw]
+                     stop before an instruction reads/writes addr (default rw)
+d, delete <addr>     remove a breakpoint or watchpoint
+i, info              list breakpoints and watchpoints
+r, regs              show registers and flags
+set <reg> <value>    set a, x, y, sp, pc, p or a flag (n v d i z c) to a hex value
+x <addr> [len]       hexdump memory (default 64 bytes)
+e <addr> <byte>...   write bytes to memory
+l, list [addr]       disassemble around PC or from addr
+bt                   call stack
+q, quit              exit the emulator
+Numbers are hex, with an optional $ or 0x prefix.";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    pub fn matches(&self, other: Access) -> bool {
+        *self == Access::ReadWrite || other == Access::ReadWrite || *self == other
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunMode {
+    Paused,
+    Running,
+    /// instructions left before pausing again
+    Step(usize),
+    /// run until `addr` is reached with at most `depth` frames on the call stack
+    Until { addr: u16, depth: usize },
+}
+
+/// Something that can stop the CPU between instructions and take commands while
+/// it is stopped: the REPL `Debugger` or a `GdbStub` connection.
+pub trait Monitor {
+    /// Call before every instruction. Returns `true` when execution should pause.
+    fn before_instruction(&mut self, cpu: &CPU) -> bool;
+
+    fn is_paused(&self) -> bool;
+
+    fn quit_requested(&self) -> bool;
+
+    /// Blocks for commands until execution resumes or the user quits.
+    fn wait(&mut self, cpu: &mut CPU);
+
+    /// Runs the commands already received; for frontends that must keep polling events.
+    fn poll(&mut self, cpu: &mut CPU);
+}
+
+/// A subroutine call seen on the way in; popped by the matching RTS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub call_site: u16,
+    pub target: u16,
+}
+
+/// REPL debugger driven by the frontend before every instruction.
+///
+/// Commands arrive as lines on a channel, usually from `stdin_lines`, so the SDL
+/// frontend can keep its window responsive while paused. Watchpoints look at the
+/// operand of the instruction about to run and stop before it accesses memory.
+pub struct Debugger {
+    input: Receiver<String>,
+    mode: RunMode,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: BTreeMap<u16, Access>,
+    call_stack: Vec<Frame>,
+    history: VecDeque<u16>,
+    quit: bool,
+}
+
+/// Lines read from stdin on a background thread.
+pub fn stdin_lines() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let line = line.unwrap_or_else(|_| "quit".to_string());
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+        // end of input
+        let _ = sender.send("quit".to_string());
+    });
+    receiver
+}
+
+impl Debugger {
+    /// Starts paused, so breakpoints can be set before the first instruction.
+    pub fn new(input: Receiver<String>) -> Self {
+        Debugger {
+            input,
+            mode: RunMode::Paused,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            call_stack: vec![],
+            history: VecDeque::new(),
+            quit: false,
+        }
+    }
+
+    /// Why execution should pause before this instruction; empty when a step finished.
+    fn stop_reason(&mut self, cpu: &CPU) -> Option<String> {
+        let pc = cpu.program_counter;
+        match self.mode {
+            RunMode::Paused => return None,
+            RunMode::Step(0) => return Some(String::new()),
+            RunMode::Step(left) => self.mode = RunMode::Step(left - 1),
+            RunMode::Until { addr, depth } if addr == pc && self.call_stack.len() <= depth => {
+                return Some(String::new());
+            }
+            _ => {}
+        }
+
+        if self.breakpoints.contains(&pc) {
+            return Some(format!("breakpoint at ${:04X}", pc));
+        }
+        let (addr, access) = operand_access(cpu)?;
+        match self.watchpoints.get(&addr) {
+            Some(watch) if watch.matches(access) => Some(format!(
+                "watchpoint ${:04X} ({}) at ${:04X}",
+                addr,
+                access_name(access),
+                pc
+            )),
+            _ => None,
+        }
+    }
+
+    /// Records the instruction about to run, for `list` and `bt`.
+    fn track(&mut self, cpu: &CPU) {
+        let pc = cpu.program_counter;
+        self.history.push_back(pc);
+        if self.history.len() > HISTORY_LEN {
+            self.history.pop_front();
+        }
+
+        match cpu.mem_peek(pc) {
+            0x20 => self.call_stack.push(Frame { call_site: pc, target: cpu.mem_peek_u16(pc.wrapping_add(1)) }),
+            0x60 => {
+                self.call_stack.pop();
+            }
+            _ => {}
+        }
+    }
+
+    fn resume(&mut self, cpu: &CPU, mode: RunMode) {
+        self.mode = mode;
+        self.track(cpu);
+    }
+
+    fn print(&mut self, cpu: &mut CPU, line: &str) {
+        let output = self.execute(cpu, line);
+        if !output.is_empty() {
+            println!("{}", output);
+        }
+    }
+
+    /// Runs one command line and returns its output.
+    pub fn execute(&mut self, cpu: &mut CPU, line: &str) -> String {
+        match self.command(cpu, line) {
+            Ok(output) => output,
+            Err(e) => format!("error: {}", e),
+        }
+    }
+
+    fn command(&mut self, cpu: &mut CPU, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Ok(String::new()),
+        };
+        let arg = |i: usize| -> Result<u16, String> {
+            parse_address(args.get(i).ok_or(format!("{} needs an address", name))?)
+        };
+
+        match name {
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(count) => parse_address(count).map_err(|_| format!("bad count {}", count))? as usize,
+                    None => 1,
+                };
+                if count == 0 {
+                    return Err("step count must be at least 1".to_string());
+                }
+                self.resume(cpu, RunMode::Step(count - 1));
+            }
+            "n" | "next" => {
+                let pc = cpu.program_counter;
+                let mode = if cpu.mem_peek(pc) == 0x20 {
+                    RunMode::Until { addr: pc.wrapping_add(3), depth: self.call_stack.len() }
+                } else {
+                    RunMode::Step(0)
+                };
+                self.resume(cpu, mode);
+            }
+            "f" | "finish" => {
+                let frame = self.call_stack.last().ok_or("not inside a subroutine")?;
+                let mode = RunMode::Until {
+                    addr: frame.call_site.wrapping_add(3),
+                    depth: self.call_stack.len() - 1,
+                };
+                self.resume(cpu, mode);
+            }
+            "c" | "continue" => self.resume(cpu, RunMode::Running),
+            "b" | "break" => {
+                let addr = arg(0)?;
+                self.breakpoints.insert(addr);
+                return Ok(format!("breakpoint at ${:04X}", addr));
+            }
+            "w" | "watch" => {
+                let addr = arg(0)?;
+                let access = match args.get(1).copied() {
+                    None | Some("rw") => Access::ReadWrite,
+                    Some("r") => Access::Read,
+                    Some("w") => Access::Write,
+                    Some(other) => return Err(format!("watch kind is r, w or rw, not {}", other)),
+                };
+                self.watchpoints.insert(addr, access);
+                return Ok(format!("watchpoint ${:04X} ({})", addr, access_name(access)));
+            }
+            "d" | "delete" => {
+                let addr = arg(0)?;
+                let removed = self.breakpoints.remove(&addr) | self.watchpoints.remove(&addr).is_some();
+                if !removed {
+                    return Err(format!("nothing set at ${:04X}", addr));
+                }
+            }
+            "i" | "info" => {
+                let mut out = vec![];
+                for addr in &self.breakpoints {
+                    out.push(format!("breakpoint ${:04X}", addr));
+                }
+                for (addr, access) in &self.watchpoints {
+                    out.push(format!("watchpoint ${:04X} ({})", addr, access_name(*access)));
+                }
+                return Ok(out.join("\n"));
+            }
+            "r" | "regs" => return Ok(registers(cpu)),
+            "set" => {
+                let register = args.first().ok_or("set needs a register")?.to_ascii_lowercase();
+                let value = arg(1)?;
+                set_register(cpu, &register, value)?;
+                return Ok(registers(cpu));
+            }
+            "x" => {
+                let addr = arg(0)?;
+                let len = if args.len() > 1 { arg(1)? } else { 64 };
+                return Ok(hexdump(cpu, addr, len));
+            }
+            "e" => {
+                let addr = arg(0)?;
+                if args.len() < 2 {
+                    return Err("e needs bytes to write".to_string());
+                }
+                for (i, text) in args[1..].iter().enumerate() {
+                    let byte = parse_address(text)?;
+                    if byte > 0xff {
+                        return Err(format!("{} is not a byte", text));
+                    }
+                    cpu.mem_write(addr.wrapping_add(i as u16), byte as u8);
+                }
+            }
+            "l" | "list" => {
+                return Ok(match args.first() {
+                    Some(_) => listing(cpu, arg(0)?, 10, None),
+                    None => listing_around(cpu, &self.history),
+                });
+            }
+            "bt" | "backtrace" => {
+                if self.call_stack.is_empty() {
+                    return Ok("not inside a subroutine".to_string());
+                }
+                let frames = self.call_stack.iter().rev().enumerate();
+                return Ok(frames
+                    .map(|(i, frame)| {
+                        format!("#{} ${:04X} called from ${:04X}", i, frame.target, frame.call_site)
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n"));
+            }
+            "q" | "quit" => self.quit = true,
+            "h" | "help" | "?" => return Ok(HELP.to_string()),
+            _ => return Err(format!("unknown command {}, try help", name)),
+        }
+        Ok(String::new())
+    }
+
+    fn current(&self, cpu: &CPU) -> String {
+        listing(cpu, cpu.program_counter, 1, Some(cpu.program_counter))
+    }
+}
+
+impl Monitor for Debugger {
+    fn is_paused(&self) -> bool {
+        self.mode == RunMode::Paused
+    }
+
+    fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    fn before_instruction(&mut self, cpu: &CPU) -> bool {
+        if let Some(reason) = self.stop_reason(cpu) {
+            self.mode = RunMode::Paused;
+            if !reason.is_empty() {
+                println!("{}", reason);
+            }
+            println!("{}", self.current(cpu));
+            return true;
+        }
+        if self.is_paused() {
+            println!("{}", self.current(cpu));
+            return true;
+        }
+        self.track(cpu);
+        false
+    }
+
+    fn wait(&mut self, cpu: &mut CPU) {
+        while self.is_paused() && !self.quit {
+            match self.input.recv() {
+                Ok(line) => self.print(cpu, &line),
+                Err(_) => self.quit = true,
+            }
+        }
+    }
+
+    fn poll(&mut self, cpu: &mut CPU) {
+        while self.is_paused() && !self.quit {
+            match self.input.try_recv() {
+                Ok(line) => self.print(cpu, &line),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => self.quit = true,
+            }
+        }
+    }
+}
+
+/// Memory operand the instruction at the program counter is about to access.
+pub fn operand_access(cpu: &CPU) -> Option<(u16, Access)> {
+    let instruction = disasm::decode_with(cpu.opcodes(), cpu, cpu.program_counter);
+    let op = instruction.opcode?;
+    let arg = *instruction.bytes.get(1)?;
+    let arg_u16 = || (*instruction.bytes.get(2).unwrap_or(&0) as u16) << 8 | arg as u16;
+    let zero_page_u16 = |ptr: u8| {
+        (cpu.mem_peek(ptr.wrapping_add(1) as u16) as u16) << 8 | cpu.mem_peek(ptr as u16) as u16
+    };
+
+    let addr = match op.mode {
+        AddressingMode::ZeroPage => arg as u16,
+        AddressingMode::ZeroPage_X => arg.wrapping_add(cpu.register_x) as u16,
+        AddressingMode::ZeroPage_Y => arg.wrapping_add(cpu.register_y) as u16,
+        AddressingMode::Absolute => arg_u16(),
+        AddressingMode::Absolute_X => arg_u16().wrapping_add(cpu.register_x as u16),
+        AddressingMode::Absolute_Y => arg_u16().wrapping_add(cpu.register_y as u16),
+        AddressingMode::Indirect_X => zero_page_u16(arg.wrapping_add(cpu.register_x)),
+        AddressingMode::Indirect_Y => zero_page_u16(arg).wrapping_add(cpu.register_y as u16),
+        AddressingMode::ZeroPage_Indirect => zero_page_u16(arg),
+        AddressingMode::Immediate | AddressingMode::NoneAddressing => return None,
+    };
+    let access = match op.mnemonic {
+        "STA" | "STX" | "STY" | "STZ" => Access::Write,
+        "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC" | "TRB" | "TSB" => Access::ReadWrite,
+        _ => Access::Read,
+    };
+    Some((addr, access))
+}
+
+pub fn access_name(access: Access) -> &'static str {
+    match access {
+        Access::Read => "r",
+        Access::Write => "w",
+        Access::ReadWrite => "rw",
+    }
+}
+
+fn registers(cpu: &CPU) -> String {
+    let flags: String = "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, name)| {
+            if cpu.status.bits() & (0x80 >> i) != 0 {
+                name
+            } else {
+                name.to_ascii_lowercase()
+            }
+        })
+        .collect();
+    format!(
+        "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} P:{:02X} [{}] CYC:{}",
+        cpu.program_counter,
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.stack_pointer,
+        cpu.status.bits(),
+        flags,
+        cpu.cycles
+    )
+}
+
+fn set_register(cpu: &mut CPU, register: &str, value: u16) -> Result<(), String> {
+    let byte = || -> Result<u8, String> {
+        u8::try_from(value).map_err(|_| format!("${:X} does not fit in {}", value, register))
+    };
+    let flag = match register {
+        "n" => CpuFlags::NEGATIV,
+        "v" => CpuFlags::OVERFLOW,
+        "d" => CpuFlags::DECIMAL_MODE,
+        "i" => CpuFlags::INTERRUPT_DISABLE,
+        "z" => CpuFlags::ZERO,
+        "c" => CpuFlags::CARRY,
+        "a" => return byte().map(|v| cpu.register_a = v),
+        "x" => return byte().map(|v| cpu.register_x = v),
+        "y" => return byte().map(|v| cpu.register_y = v),
+        "sp" => return byte().map(|v| cpu.stack_pointer = v),
+        "p" => return byte().map(|v| cpu.status = CpuFlags::from_bits_truncate(v)),
+        "pc" => {
+            cpu.program_counter = value;
+            return Ok(());
+        }
+        _ => return Err(format!("unknown register {}", register)),
+    };
+    cpu.status.set(flag, value != 0);
+    Ok(())
+}
+
+fn hexdump(cpu: &CPU, addr: u16, len: u16) -> String {
+    let mut lines = vec![];
+    let end = addr as u32 + len as u32;
+    let mut line_start = addr as u32;
+    while line_start < end && line_start <= 0xffff {
+        let bytes: Vec<u8> = (line_start..end.min(line_start + 16).min(0x10000))
+            .map(|a| cpu.mem_peek(a as u16))
+            .collect();
+        let hex = bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" ");
+        let text: String = bytes
+            .iter()
+            .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+            .collect();
+        lines.push(format!("{:04X}  {:47}  |{}|", line_start, hex, text));
+        line_start += 16;
+    }
+    lines.join("\n")
+}
+
+/// `count` instructions from `addr`, marking `current` with `>`.
+fn listing(cpu: &CPU, addr: u16, count: usize, current: Option<u16>) -> String {
+    let mut lines = vec![];
+    let mut addr = addr;
+    for _ in 0..count {
+        let instruction = disasm::decode_with(cpu.opcodes(), cpu, addr);
+        let hex = instruction.bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" ");
+        let line = format!(
+            "{} {:04X}  {:8}  {} {}",
+            if current == Some(addr) { '>' } else { ' ' },
+            addr,
+            hex,
+            instruction.mnemonic(),
+            instruction.operand(&BTreeMap::new())
+        );
+        lines.push(line.trim_end().to_string());
+        addr = addr.wrapping_add(instruction.bytes.len() as u16);
+    }
+    lines.join("\n")
+}
+
+/// The last executed instructions followed by the ones at the program counter.
+fn listing_around(cpu: &CPU, history: &VecDeque<u16>) -> String {
+    let pc = cpu.program_counter;
+    let mut lines: Vec<String> = history
+        .iter()
+        .filter(|addr| **addr != pc)
+        .map(|addr| listing(cpu, *addr, 1, None))
+        .collect();
+    lines.push(listing(cpu, pc, 6, Some(pc)));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::assemble;
+    use std::sync::mpsc::Sender;
+
+    fn setup(source: &str) -> (CPU, Debugger, Sender<String>) {
+        let mut cpu = CPU::new();
+        cpu.load(assemble(source, cpu.variant()).unwrap()).unwrap();
+        cpu.reset();
+        let (sender, receiver) = mpsc::channel();
+        (cpu, Debugger::new(receiver), sender)
+    }
+
+    /// Runs like a frontend after resuming, returning the program counter where the
+    /// debugger paused.
+    fn run_until_pause(cpu: &mut CPU, debugger: &mut Debugger) -> u16 {
+        loop {
+            assert!(cpu.step().unwrap(), "program ended");
+            if debugger.before_instruction(cpu) {
+                return cpu.program_counter;
+            }
+        }
+    }
+
+    const PROGRAM: &str = "
+            LDX #0
+    loop:   JSR add
+            INX
+            CPX #3
+            BNE loop
+            BRK
+    add:    INC $10
+            LDA $10
+            RTS
+    ";
+
+    #[test]
+    fn test_breakpoint_and_call_stack() {
+        let (mut cpu, mut debugger, _input) = setup(PROGRAM);
+        debugger.execute(&mut cpu, "b 060b");
+        debugger.execute(&mut cpu, "c");
+
+        assert_eq!(run_until_pause(&mut cpu, &mut debugger), 0x060b);
+        assert_eq!(debugger.execute(&mut cpu, "bt"), "#0 $060B called from $0602");
+
+        debugger.execute(&mut cpu, "finish");
+        assert_eq!(run_until_pause(&mut cpu, &mut debugger), 0x0605);
+        assert_eq!(debugger.execute(&mut cpu, "bt"), "not inside a subroutine");
+
+        debugger.execute(&mut cpu, "c");
+        assert_eq!(run_until_pause(&mut cpu, &mut debugger), 0x060b);
+        assert_eq!(cpu.memory[0x10], 1);
+    }
+
+    #[test]
+    fn test_step_and_next() {
+        let (mut cpu, mut debugger, _input) = setup(PROGRAM);
+        debugger.execute(&mut cpu, "step 2");
+        assert_eq!(run_until_pause(&mut cpu, &mut debugger), 0x060b);
+
+        debugger.execute(&mut cpu, "set pc 0602");
+        debugger.execute(&mut cpu, "next");
+        assert_eq!(run_until_pause(&mut cpu, &mut debugger), 0x0605);
+        assert_eq!(cpu.memory[0x10], 1);
+    }
+
+    #[test]
+    fn test_step_count_is_hex() {
+        let (mut cpu, mut debugger, _input) = setup(&"NOP\n".repeat(0x20));
+        debugger.execute(&mut cpu, "step 10");
+        assert_eq!(run_until_pause(&mut cpu, &mut debugger), 0x0610);
+        debugger.execute(&mut cpu, "step $a");
+        assert_eq!(run_until_pause(&mut cpu, &mut debugger), 0x061a);
+        assert!(debugger.execute(&mut cpu, "step 12g").starts_with("error"));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let (mut cpu, mut debugger, _input) = setup("LDA $10\nSTA $10\nLDX #5\nSTA $10,X\nBRK");
+        debugger.execute(&mut cpu, "watch 10 w");
+        debugger.execute(&mut cpu, "watch 15");
+        debugger.execute(&mut cpu, "c");
+
+        assert_eq!(run_until_pause(&mut cpu, &mut debugger), 0x0602);
+        debugger.execute(&mut cpu, "c");
+        assert_eq!(run_until_pause(&mut cpu, &mut debugger), 0x0606);
+        assert_eq!(debugger.execute(&mut cpu, "info"), "watchpoint $0010 (w)\nwatchpoint $0015 (rw)");
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let (mut cpu, mut debugger, _input) = setup("BRK");
+        debugger.execute(&mut cpu, "set a 42");
+        debugger.execute(&mut cpu, "set c 1");
+        assert_eq!(
+            debugger.execute(&mut cpu, "regs"),
+            "PC:0600 A:42 X:00 Y:00 SP:FD P:25 [nv-bdIzC] CYC:7"
+        );
+        assert!(debugger.execute(&mut cpu, "set a 100").starts_with("error"));
+
+        debugger.execute(&mut cpu, "e $20 48 49 ff");
+        assert_eq!(
+            debugger.execute(&mut cpu, "x 20 4"),
+            "0020  48 49 FF 00                                      |HI..|"
+        );
+        assert_eq!(debugger.execute(&mut cpu, "l"), "> 0600  00        BRK\n  0601  00        BRK\n  0602  00        BRK\n  0603  00        BRK\n  0604  00        BRK\n  0605  00        BRK");
+    }
+}
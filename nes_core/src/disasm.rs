@@ -0,0 +1,267 @@
+use crate::cartridge::Rom;
+use crate::cpu::AddressingMode;
+use crate::cpu::CpuVariant;
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::error::EmuError;
+use crate::opcode;
+use crate::opcode::OpCode;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+const VECTORS: [(u16, &str); 3] = [(0xfffa, "NMI"), (0xfffc, "RESET"), (0xfffe, "IRQ")];
+
+/// One decoded instruction, or a single data byte when the opcode is unknown.
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub opcode: Option<&'static OpCode>,
+}
+
+impl Instruction {
+    /// Absolute address a branch, JMP or JSR transfers control to.
+    pub fn target(&self) -> Option<u16> {
+        let op = self.opcode?;
+        match (op.code, op.len) {
+            (0x4c, _) | (0x20, _) => Some(self.operand_u16()),
+            (_, 2) if op.mnemonic.starts_with('B') && op.mnemonic != "BIT" => Some(
+                self.addr
+                    .wrapping_add(2)
+                    .wrapping_add(self.bytes[1] as i8 as u16),
+            ),
+            _ => None,
+        }
+    }
+
+    fn operand_u16(&self) -> u16 {
+        (self.bytes[2] as u16) << 8 | self.bytes[1] as u16
+    }
+
+    /// Operand in assembler syntax, using `labels` for control flow targets.
+    pub fn operand(&self, labels: &BTreeMap<u16, String>) -> String {
+        let op = match self.opcode {
+            Some(op) => op,
+            None => return format!("${:02X}", self.bytes[0]),
+        };
+        if let Some(target) = self.target() {
+            return match labels.get(&target) {
+                Some(label) => label.clone(),
+                None => format!("${:04X}", target),
+            };
+        }
+
+        match (op.len, &op.mode) {
+            (1, _) => match op.code {
+                0x0a | 0x4a | 0x2a | 0x6a | 0x1a | 0x3a => "A".to_string(),
+                _ => String::new(),
+            },
+            (2, AddressingMode::Immediate) => format!("#${:02X}", self.bytes[1]),
+            (2, AddressingMode::ZeroPage) => format!("${:02X}", self.bytes[1]),
+            (2, AddressingMode::ZeroPage_X) => format!("${:02X},X", self.bytes[1]),
+            (2, AddressingMode::ZeroPage_Y) => format!("${:02X},Y", self.bytes[1]),
+            (2, AddressingMode::Indirect_X) => format!("(${:02X},X)", self.bytes[1]),
+            (2, AddressingMode::Indirect_Y) => format!("(${:02X}),Y", self.bytes[1]),
+            (2, AddressingMode::ZeroPage_Indirect) => format!("(${:02X})", self.bytes[1]),
+            (3, AddressingMode::Absolute_X) => format!("${:04X},X", self.operand_u16()),
+            (3, AddressingMode::Absolute_Y) => format!("${:04X},Y", self.operand_u16()),
+            (3, _) if op.code == 0x6c => format!("(${:04X})", self.operand_u16()),
+            (3, _) if op.code == 0x7c => format!("(${:04X},X)", self.operand_u16()),
+            (3, _) => format!("${:04X}", self.operand_u16()),
+            _ => String::new(),
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        self.opcode.map_or(".byte", |op| op.mnemonic)
+    }
+}
+
+/// Decodes the instruction of `variant` at `addr`. Operand bytes wrap around at $FFFF.
+pub fn decode<M: Mem>(variant: CpuVariant, mem: &M, addr: u16) -> Instruction {
+    decode_with(opcode::opcodes_map(variant), mem, addr)
+}
+
+/// Decodes with another instruction set, such as `CPU::opcodes`.
+pub fn decode_with<M: Mem>(opcodes: &HashMap<u8, &'static OpCode>, mem: &M, addr: u16) -> Instruction {
+    let code = mem.mem_peek(addr);
+    let opcode = opcodes.get(&code).copied();
+    let len = opcode.map_or(1, |op| op.len);
+    let bytes = (0..len as u16)
+        .map(|i| mem.mem_peek(addr.wrapping_add(i)))
+        .collect();
+    Instruction { addr, bytes, opcode }
+}
+
+/// Disassembles `start..=end` into a listing like
+///
+/// ```text
+/// RESET:
+///   C000  A2 08     LDX #$08
+/// L_C002:
+///   C002  CA        DEX
+///   C003  D0 FD     BNE L_C002
+/// ```
+///
+/// Code is decoded linearly from `start`. Branch and jump targets inside the range get
+/// `L_xxxx` labels; the interrupt vectors name their handlers and, when the range covers
+/// $FFFA-$FFFF, are listed as `.word` entries instead of code.
+pub fn disassemble<M: Mem>(variant: CpuVariant, mem: &M, start: u16, end: u16) -> String {
+    let code_end = if end >= 0xfffa && start < 0xfffa { 0xfff9 } else { end };
+
+    let mut instructions = vec![];
+    let mut addr = start as u32;
+    while addr <= code_end as u32 {
+        let instruction = decode(variant, mem, addr as u16);
+        addr += instruction.bytes.len() as u32;
+        instructions.push(instruction);
+    }
+
+    let in_range = |addr: u16| addr >= start && addr <= code_end;
+    let mut labels = BTreeMap::new();
+    for instruction in &instructions {
+        if let Some(target) = instruction.target().filter(|addr| in_range(*addr)) {
+            labels.insert(target, format!("L_{:04X}", target));
+        }
+    }
+    for (vector, name) in VECTORS {
+        let handler = mem.mem_peek_u16(vector);
+        if in_range(handler) {
+            labels.insert(handler, name.to_string());
+        }
+    }
+
+    let mut out = String::new();
+    for instruction in &instructions {
+        if let Some(label) = labels.get(&instruction.addr) {
+            out.push_str(&format!("{}:\n", label));
+        }
+        let hex = instruction
+            .bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let line = format!(
+            "  {:04X}  {:8}  {} {}",
+            instruction.addr,
+            hex,
+            instruction.mnemonic(),
+            instruction.operand(&labels)
+        );
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+
+    if code_end != end {
+        for (vector, name) in VECTORS.iter().filter(|(vector, _)| *vector <= end) {
+            let handler = mem.mem_peek_u16(*vector);
+            let operand = match labels.get(&handler) {
+                Some(label) => label.clone(),
+                None => format!("${:04X}", handler),
+            };
+            out.push_str(&format!(
+                "  {:04X}  {:02X} {:02X}     .word {} ; {}\n",
+                vector,
+                handler as u8,
+                handler >> 8,
+                operand,
+                name
+            ));
+        }
+    }
+    out
+}
+
+/// Disassembles the PRG-ROM of an NROM cartridge as it is mapped into the CPU:
+/// a 16KB bank at $C000-$FFFF, 32KB at $8000-$FFFF.
+pub fn disassemble_rom(variant: CpuVariant, rom: &Rom) -> Result<String, EmuError> {
+    let mut cpu = CPU::with_variant(variant);
+    cpu.load_rom(rom)?;
+    let start = if rom.prg_rom.len() <= 0x4000 { 0xc000 } else { 0x8000 };
+    Ok(disassemble(variant, &cpu, start, 0xffff))
+}
+
+/// Parses a hex address written as `C000`, `$C000` or `0xC000`.
+pub fn parse_address(text: &str) -> Result<u16, String> {
+    let digits = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("{} is not a 16-bit hex address", text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_labels_branch_targets() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xa2, 0x08, 0xca, 0xd0, 0xfd, 0x20, 0x0c, 0x06, 0x0a, 0x6c, 0x00, 0x02, 0x60]).unwrap();
+
+        assert_eq!(
+            disassemble(CpuVariant::Ricoh2A03, &cpu, 0x0600, 0x060c),
+            "RESET:\n\
+             \x20 0600  A2 08     LDX #$08\n\
+             L_0602:\n\
+             \x20 0602  CA        DEX\n\
+             \x20 0603  D0 FD     BNE L_0602\n\
+             \x20 0605  20 0C 06  JSR L_060C\n\
+             \x20 0608  0A        ASL A\n\
+             \x20 0609  6C 00 02  JMP ($0200)\n\
+             L_060C:\n\
+             \x20 060C  60        RTS\n"
+        );
+    }
+
+    #[test]
+    fn test_unknown_opcodes_and_vectors() {
+        let mut prg = vec![0xea; 0x4000];
+        prg[0] = 0x02;
+        prg[0x3ffa..].copy_from_slice(&[0x00, 0xc0, 0x01, 0xc0, 0x34, 0x12]);
+        let mut cpu = CPU::new();
+        for (i, byte) in prg.iter().enumerate() {
+            cpu.mem_write(0xc000 + i as u16, *byte);
+        }
+
+        let listing = disassemble(CpuVariant::Nmos6502, &cpu, 0xc000, 0xffff);
+        assert!(listing.starts_with("NMI:\n  C000  02        .byte $02\nRESET:\n  C001  EA        NOP\n"));
+        assert!(listing.ends_with(
+            "  FFFA  00 C0     .word NMI ; NMI\n\
+             \x20 FFFC  01 C0     .word RESET ; RESET\n\
+             \x20 FFFE  34 12     .word $1234 ; IRQ\n"
+        ));
+    }
+
+    #[test]
+    fn test_decode_65c02() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xb2, 0x20, 0x3a]).unwrap();
+        let labels = BTreeMap::new();
+
+        let lda = decode(CpuVariant::Cmos65C02, &cpu, 0x0600);
+        assert_eq!((lda.mnemonic(), lda.operand(&labels)), ("LDA", "($20)".to_string()));
+        let dec = decode(CpuVariant::Cmos65C02, &cpu, 0x0602);
+        assert_eq!((dec.mnemonic(), dec.operand(&labels)), ("DEC", "A".to_string()));
+        assert_eq!(decode(CpuVariant::Nmos6502, &cpu, 0x0600).mnemonic(), ".byte");
+    }
+
+    #[test]
+    fn test_disassemble_65c02() {
+        let mut cpu = CPU::new();
+        // BRA / BIT #$80 / JMP ($0700,X) / an undefined opcode / BRA
+        cpu.load(vec![0x80, 0x02, 0x89, 0x80, 0x7c, 0x00, 0x07, 0x5c, 0x34, 0x12, 0x80, 0xf4]).unwrap();
+
+        assert_eq!(
+            disassemble(CpuVariant::Cmos65C02, &cpu, 0x0600, 0x060b),
+            "RESET:\n\
+             \x20 0600  80 02     BRA L_0604\n\
+             \x20 0602  89 80     BIT #$80\n\
+             L_0604:\n\
+             \x20 0604  7C 00 07  JMP ($0700,X)\n\
+             \x20 0607  5C 34 12  NOP $1234\n\
+             \x20 060A  80 F4     BRA RESET\n"
+        );
+        assert!(disassemble(CpuVariant::Nmos6502, &cpu, 0x0600, 0x060b).starts_with("RESET:\n  0600  80        .byte $80\n"));
+    }
+}
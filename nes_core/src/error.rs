@@ -0,0 +1,42 @@
+use crate::cpu::AddressingMode;
+use std::error::Error;
+use std::fmt;
+
+/// Errors the emulator core reports instead of panicking, so a frontend can show
+/// them and keep running.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmuError {
+    /// `opcode` at `pc` is not an instruction the CPU implements.
+    UnknownOpcode { opcode: u8, pc: u16 },
+    /// The instruction at `pc` has no operand address in `mode`.
+    UnsupportedAddressingMode { mode: AddressingMode, pc: u16 },
+    /// The image is not a usable iNES file.
+    InvalidRom(String),
+    UnsupportedMapper(u8),
+    /// `len` bytes do not fit in memory when loaded at `start`.
+    LoadOutOfRange { start: u16, len: usize },
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmuError::UnknownOpcode { opcode, pc } => {
+                write!(f, "unknown opcode ${:02X} at ${:04X}", opcode, pc)
+            }
+            EmuError::UnsupportedAddressingMode { mode, pc } => {
+                write!(f, "addressing mode {:?} of the instruction at ${:04X} has no operand", mode, pc)
+            }
+            EmuError::InvalidRom(reason) => write!(f, "invalid ROM: {}", reason),
+            EmuError::UnsupportedMapper(mapper) => {
+                write!(f, "mapper {} is not supported, only NROM (mapper 0) is", mapper)
+            }
+            EmuError::LoadOutOfRange { start, len } => write!(
+                f,
+                "{} bytes loaded at ${:04X} run past the end of memory",
+                len, start
+            ),
+        }
+    }
+}
+
+impl Error for EmuError {}
@@ -0,0 +1,526 @@
+use crate::debugger::access_name;
+use crate::debugger::operand_access;
+use crate::debugger::Access;
+use crate::debugger::Monitor;
+use crate::cpu::CpuFlags;
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use log::debug;
+use log::info;
+use log::warn;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+
+/// Instructions between two checks for a ctrl-c from gdb while running.
+const INTERRUPT_POLL_INTERVAL: usize = 1024;
+
+/// Registers in `g`/`G`/`p`/`P` order: A, X, Y, P and SP as one byte each, then PC
+/// as two bytes, little endian.
+const REGISTER_COUNT: usize = 6;
+
+/// GDB remote serial protocol stub https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+///
+/// Supports register and memory access, software and hardware breakpoints (both are
+/// PC breakpoints here), write/read/access watchpoints, single-step and continue.
+/// Like the REPL debugger, watchpoints stop before the instruction that accesses memory.
+pub struct GdbStub {
+    connection: Option<Connection>,
+    target: Target,
+    instructions: usize,
+}
+
+impl GdbStub {
+    /// Waits for gdb to connect to 127.0.0.1:`port`; the CPU starts stopped.
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!("waiting for gdb on 127.0.0.1:{} (target remote :{})", port, port);
+        let (stream, peer) = listener.accept()?;
+        info!("gdb connected from {}", peer);
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            connection: Some(Connection { stream, buffer: vec![] }),
+            target: Target::new(),
+            instructions: 0,
+        })
+    }
+
+    /// Handles everything gdb sent; `blocking` waits for at least one packet.
+    fn serve(&mut self, cpu: &mut CPU, blocking: bool) {
+        while let Some(connection) = &mut self.connection {
+            let incoming = match connection.receive(blocking) {
+                Ok(Some(incoming)) => incoming,
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("gdb connection closed: {}", e);
+                    self.detach();
+                    return;
+                }
+            };
+            let reply = match incoming {
+                Incoming::Packet(packet) => {
+                    connection.send_ack();
+                    self.target.handle(cpu, &packet)
+                }
+                Incoming::Interrupt => {
+                    self.target.mode = Mode::Paused;
+                    Some(format!("S{:02x}", SIGTRAP))
+                }
+                Incoming::BadChecksum => {
+                    connection.send_nack();
+                    None
+                }
+            };
+            if let Some(reply) = reply {
+                if let Err(e) = connection.send(&reply) {
+                    warn!("gdb connection closed: {}", e);
+                    self.detach();
+                    return;
+                }
+            }
+            if self.target.detached {
+                self.detach();
+                return;
+            }
+            if !self.target.is_paused() || self.target.quit {
+                return;
+            }
+        }
+    }
+
+    /// While running, gdb only sends ctrl-c; anything else waits for the next stop.
+    fn check_interrupt(&mut self) {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => return,
+        };
+        match connection.receive(false) {
+            Ok(Some(Incoming::Interrupt)) => self.target.mode = Mode::Step,
+            Ok(Some(packet)) => debug!("gdb sent {:?} while running, ignored", packet),
+            Ok(None) => {}
+            Err(e) => {
+                warn!("gdb connection closed: {}", e);
+                self.detach();
+            }
+        }
+    }
+
+    fn detach(&mut self) {
+        info!("gdb detached, running freely");
+        self.connection = None;
+        self.target.mode = Mode::Running;
+    }
+}
+
+impl Monitor for GdbStub {
+    fn before_instruction(&mut self, cpu: &CPU) -> bool {
+        if self.connection.is_none() {
+            return false;
+        }
+        self.instructions += 1;
+        if self.instructions.is_multiple_of(INTERRUPT_POLL_INTERVAL) && !self.target.is_paused() {
+            self.check_interrupt();
+        }
+
+        if let Some(reply) = self.target.stop_reason(cpu) {
+            if let Some(connection) = &mut self.connection {
+                if let Err(e) = connection.send(&reply) {
+                    warn!("gdb connection closed: {}", e);
+                    self.detach();
+                    return false;
+                }
+            }
+            return true;
+        }
+        self.target.is_paused()
+    }
+
+    fn is_paused(&self) -> bool {
+        self.target.is_paused()
+    }
+
+    fn quit_requested(&self) -> bool {
+        self.target.quit
+    }
+
+    fn wait(&mut self, cpu: &mut CPU) {
+        self.serve(cpu, true);
+    }
+
+    fn poll(&mut self, cpu: &mut CPU) {
+        self.serve(cpu, false);
+    }
+}
+
+const SIGTRAP: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Paused,
+    Running,
+    Step,
+}
+
+/// The protocol state, independent of the socket.
+struct Target {
+    mode: Mode,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: BTreeMap<u16, Access>,
+    quit: bool,
+    detached: bool,
+}
+
+impl Target {
+    fn new() -> Self {
+        Target {
+            mode: Mode::Paused,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            quit: false,
+            detached: false,
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    /// Stop reply to send when execution has to stop before this instruction.
+    fn stop_reason(&mut self, cpu: &CPU) -> Option<String> {
+        let reply = match self.mode {
+            Mode::Paused => return None,
+            Mode::Step => Some(format!("S{:02x}", SIGTRAP)),
+            Mode::Running if self.breakpoints.contains(&cpu.program_counter) => {
+                Some(format!("T{:02x}swbreak:;", SIGTRAP))
+            }
+            Mode::Running => operand_access(cpu).and_then(|(addr, access)| {
+                let watch = self.watchpoints.get(&addr)?;
+                if !watch.matches(access) {
+                    return None;
+                }
+                let kind = match watch {
+                    Access::Write => "watch",
+                    Access::Read => "rwatch",
+                    Access::ReadWrite => "awatch",
+                };
+                Some(format!("T{:02x}{}:{:x};", SIGTRAP, kind, addr))
+            }),
+        };
+        if reply.is_some() {
+            self.mode = Mode::Paused;
+        }
+        reply
+    }
+
+    /// Runs one packet. Returns the reply, or `None` when execution resumed and the
+    /// reply is the stop packet sent later.
+    fn handle(&mut self, cpu: &mut CPU, packet: &str) -> Option<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => registers(cpu).iter().map(|b| format!("{:02x}", b)).collect(),
+            "G" => match decode_hex(args) {
+                Some(bytes) if bytes.len() == REGISTER_COUNT + 1 => {
+                    set_registers(cpu, &bytes);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < REGISTER_COUNT => {
+                    let bytes = registers(cpu);
+                    let range = if n == REGISTER_COUNT - 1 { n..n + 2 } else { n..n + 1 };
+                    bytes[range].iter().map(|b| format!("{:02x}", b)).collect()
+                }
+                _ => "E01".to_string(),
+            },
+            "P" => self.write_register(cpu, args).unwrap_or_else(|| "E01".to_string()),
+            "m" => self.read_memory(cpu, args).unwrap_or_else(|| "E01".to_string()),
+            "M" => self.write_memory(cpu, args).unwrap_or_else(|| "E01".to_string()),
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match u16::from_str_radix(args, 16) {
+                        Ok(addr) => cpu.program_counter = addr,
+                        Err(_) => return Some("E01".to_string()),
+                    }
+                }
+                self.mode = if command == "c" { Mode::Running } else { Mode::Step };
+                return None;
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args).unwrap_or_else(|| "E01".to_string()),
+            "k" => {
+                self.quit = true;
+                return None;
+            }
+            "D" => {
+                self.detached = true;
+                "OK".to_string()
+            }
+            "q" if args.starts_with("Supported") => "PacketSize=1000;swbreak+".to_string(),
+            "q" if args == "Attached" => "1".to_string(),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn write_register(&mut self, cpu: &mut CPU, args: &str) -> Option<String> {
+        let (n, value) = args.split_once('=')?;
+        let n = usize::from_str_radix(n, 16).ok()?;
+        let value = decode_hex(value)?;
+        let mut bytes = registers(cpu);
+        match (n, value.as_slice()) {
+            (n, [byte]) if n < REGISTER_COUNT - 1 => bytes[n] = *byte,
+            (n, [lo, hi]) if n == REGISTER_COUNT - 1 => {
+                bytes[n] = *lo;
+                bytes[n + 1] = *hi;
+            }
+            _ => return None,
+        }
+        set_registers(cpu, &bytes);
+        Some("OK".to_string())
+    }
+
+    fn read_memory(&mut self, cpu: &CPU, args: &str) -> Option<String> {
+        let (addr, len) = parse_addr_len(args)?;
+        Some(
+            (0..len)
+                .map(|i| format!("{:02x}", cpu.mem_peek(addr.wrapping_add(i))))
+                .collect(),
+        )
+    }
+
+    fn write_memory(&mut self, cpu: &mut CPU, args: &str) -> Option<String> {
+        let (target, data) = args.split_once(':')?;
+        let (addr, len) = parse_addr_len(target)?;
+        let bytes = decode_hex(data)?;
+        if bytes.len() != len as usize {
+            return None;
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            cpu.mem_write(addr.wrapping_add(i as u16), *byte);
+        }
+        Some("OK".to_string())
+    }
+
+    /// `Z<type>,<addr>,<kind>`: 0/1 breakpoints, 2 write, 3 read and 4 access watchpoints.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Option<String> {
+        let (kind, rest) = args.split_once(',')?;
+        let (addr, len) = parse_addr_len(rest)?;
+        let access = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return Some("OK".to_string());
+            }
+            "2" => Access::Write,
+            "3" => Access::Read,
+            "4" => Access::ReadWrite,
+            _ => return Some(String::new()),
+        };
+        for i in 0..len.max(1) {
+            let addr = addr.wrapping_add(i);
+            if insert {
+                self.watchpoints.insert(addr, access);
+            } else {
+                self.watchpoints.remove(&addr);
+            }
+        }
+        debug!("gdb {} {} watchpoint at ${:04X}", if insert { "set" } else { "removed" }, access_name(access), addr);
+        Some("OK".to_string())
+    }
+}
+
+fn registers(cpu: &CPU) -> [u8; REGISTER_COUNT + 1] {
+    let [pc_lo, pc_hi] = cpu.program_counter.to_le_bytes();
+    [
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status.bits(),
+        cpu.stack_pointer,
+        pc_lo,
+        pc_hi,
+    ]
+}
+
+fn set_registers(cpu: &mut CPU, bytes: &[u8]) {
+    cpu.register_a = bytes[0];
+    cpu.register_x = bytes[1];
+    cpu.register_y = bytes[2];
+    cpu.status = CpuFlags::from_bits_truncate(bytes[3]);
+    cpu.stack_pointer = bytes[4];
+    cpu.program_counter = u16::from_le_bytes([bytes[5], bytes[6]]);
+}
+
+fn parse_addr_len(args: &str) -> Option<(u16, u16)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((u16::from_str_radix(addr, 16).ok()?, u16::from_str_radix(len, 16).ok()?))
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum Incoming {
+    Packet(String),
+    /// ctrl-c, sent outside of a packet
+    Interrupt,
+    BadChecksum,
+}
+
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Connection {
+    fn receive(&mut self, blocking: bool) -> io::Result<Option<Incoming>> {
+        loop {
+            if let Some(incoming) = parse_incoming(&mut self.buffer) {
+                return Ok(Some(incoming));
+            }
+            self.stream.set_nonblocking(!blocking)?;
+            let mut chunk = [0; 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        self.stream.write_all(frame(data).as_bytes())
+    }
+
+    fn send_ack(&mut self) {
+        let _ = self.stream.write_all(b"+");
+    }
+
+    fn send_nack(&mut self) {
+        let _ = self.stream.write_all(b"-");
+    }
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, b| sum.wrapping_add(b))
+}
+
+fn frame(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum(data))
+}
+
+/// Takes the next packet or interrupt off the front of `buffer`, skipping acks.
+fn parse_incoming(buffer: &mut Vec<u8>) -> Option<Incoming> {
+    while let Some(&byte) = buffer.first() {
+        match byte {
+            0x03 => {
+                buffer.remove(0);
+                return Some(Incoming::Interrupt);
+            }
+            b'$' => break,
+            _ => {
+                // '+' / '-' acks and noise
+                buffer.remove(0);
+            }
+        }
+    }
+
+    let end = buffer.iter().position(|b| *b == b'#')?;
+    if buffer.len() < end + 3 {
+        return None;
+    }
+    let data = String::from_utf8_lossy(&buffer[1..end]).into_owned();
+    let sum = std::str::from_utf8(&buffer[end + 1..end + 3])
+        .ok()
+        .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+    buffer.drain(..end + 3);
+
+    if sum == Some(checksum(&data)) {
+        Some(Incoming::Packet(data))
+    } else {
+        Some(Incoming::BadChecksum)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_packet_framing() {
+        assert_eq!(frame("OK"), "$OK#9a");
+
+        let mut buffer = b"+$g#67\x03$m0,2#f".to_vec();
+        assert_eq!(parse_incoming(&mut buffer), Some(Incoming::Packet("g".to_string())));
+        assert_eq!(parse_incoming(&mut buffer), Some(Incoming::Interrupt));
+        assert_eq!(parse_incoming(&mut buffer), None);
+
+        buffer.extend_from_slice(b"b$s#00");
+        assert_eq!(parse_incoming(&mut buffer), Some(Incoming::Packet("m0,2".to_string())));
+        assert_eq!(parse_incoming(&mut buffer), Some(Incoming::BadChecksum));
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut cpu = CPU::new();
+        let mut target = Target::new();
+        cpu.program_counter = 0x8123;
+        cpu.register_a = 0x42;
+
+        assert_eq!(target.handle(&mut cpu, "g").unwrap(), "42000024fd2381");
+        assert_eq!(target.handle(&mut cpu, "p5").unwrap(), "2381");
+        assert_eq!(target.handle(&mut cpu, "P1=07").unwrap(), "OK");
+        assert_eq!(target.handle(&mut cpu, "P5=0006").unwrap(), "OK");
+        assert_eq!((cpu.register_x, cpu.program_counter), (0x07, 0x0600));
+        assert_eq!(target.handle(&mut cpu, "G0102032401ff00").unwrap(), "OK");
+        assert_eq!((cpu.register_a, cpu.stack_pointer, cpu.program_counter), (1, 1, 0x00ff));
+
+        assert_eq!(target.handle(&mut cpu, "M10,3:a1b2c3").unwrap(), "OK");
+        assert_eq!(target.handle(&mut cpu, "m10,4").unwrap(), "a1b2c300");
+        assert_eq!(target.handle(&mut cpu, "M10,3:a1").unwrap(), "E01");
+        assert_eq!(target.handle(&mut cpu, "vMustReplyEmpty").unwrap(), "");
+    }
+
+    #[test]
+    fn test_breakpoints_watchpoints_and_step() {
+        let mut cpu = CPU::new();
+        // LDA $10 / STA $11 / INX / BRK
+        cpu.load(vec![0xa5, 0x10, 0x85, 0x11, 0xe8, 0x00]).unwrap();
+        cpu.reset();
+        let mut target = Target::new();
+
+        assert_eq!(target.handle(&mut cpu, "Z2,11,1").unwrap(), "OK");
+        assert_eq!(target.handle(&mut cpu, "Z0,604,1").unwrap(), "OK");
+        assert_eq!(target.handle(&mut cpu, "c"), None);
+        assert_eq!(target.stop_reason(&cpu), None);
+        cpu.step().unwrap();
+        assert_eq!(target.stop_reason(&cpu).unwrap(), "T05watch:11;");
+
+        target.handle(&mut cpu, "c");
+        cpu.step().unwrap();
+        assert_eq!(target.stop_reason(&cpu).unwrap(), "T05swbreak:;");
+
+        assert_eq!(target.handle(&mut cpu, "z0,604,1").unwrap(), "OK");
+        target.handle(&mut cpu, "s");
+        cpu.step().unwrap();
+        assert_eq!(target.stop_reason(&cpu).unwrap(), "S05");
+        assert_eq!(cpu.register_x, 1);
+    }
+}
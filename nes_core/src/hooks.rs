@@ -0,0 +1,64 @@
+use std::ops::RangeInclusive;
+
+bitflags! {
+    /// Kinds of memory traffic a hook can observe.
+    ///
+    /// `FETCH` is the opcode byte of every instruction; operand bytes and data are `READ`s.
+    /// `DUMMY` marks the reads and writes of `CPU::cycle_accurate_bus` whose data is
+    /// discarded; hooks only see them when they ask for it.
+    pub struct MemoryAccess: u8 {
+        const READ  = 0b0001;
+        const WRITE = 0b0010;
+        const FETCH = 0b0100;
+        const DUMMY = 0b1000;
+    }
+}
+
+/// Called with the kind of access (a single flag, plus `DUMMY` for dummy accesses), the
+/// address and the byte read or written.
+pub type HookFn = Box<dyn FnMut(MemoryAccess, u16, u8)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookId(usize);
+
+struct Hook {
+    id: HookId,
+    access: MemoryAccess,
+    range: RangeInclusive<u16>,
+    callback: HookFn,
+}
+
+/// Observers of memory traffic, filtered by access kind and address range.
+#[derive(Default)]
+pub struct MemoryHooks {
+    hooks: Vec<Hook>,
+    next_id: usize,
+}
+
+impl MemoryHooks {
+    pub fn add(&mut self, access: MemoryAccess, range: RangeInclusive<u16>, callback: HookFn) -> HookId {
+        let id = HookId(self.next_id);
+        self.next_id += 1;
+        self.hooks.push(Hook { id, access, range, callback });
+        id
+    }
+
+    /// Returns `false` when `id` was already removed.
+    pub fn remove(&mut self, id: HookId) -> bool {
+        let len = self.hooks.len();
+        self.hooks.retain(|hook| hook.id != id);
+        self.hooks.len() != len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub fn fire(&mut self, access: MemoryAccess, addr: u16, data: u8) {
+        for hook in &mut self.hooks {
+            if hook.access.contains(access) && hook.range.contains(&addr) {
+                (hook.callback)(access, addr, data);
+            }
+        }
+    }
+}
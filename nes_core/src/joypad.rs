@@ -0,0 +1,16 @@
+bitflags! {
+    /// # Standard controller https://wiki.nesdev.com/w/index.php/Standard_controller
+    ///
+    /// Bits are ordered like the FM2 input log: `RLDUTSBA`.
+    #[derive(Default)]
+    pub struct JoypadButton: u8 {
+        const RIGHT    = 0b10000000;
+        const LEFT     = 0b01000000;
+        const DOWN     = 0b00100000;
+        const UP       = 0b00010000;
+        const START    = 0b00001000;
+        const SELECT   = 0b00000100;
+        const BUTTON_B = 0b00000010;
+        const BUTTON_A = 0b00000001;
+    }
+}
@@ -0,0 +1,28 @@
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate bitflags;
+
+pub mod asm;
+pub mod cartridge;
+pub mod cdl;
+pub mod cpu;
+#[cfg(feature = "debugger")]
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+#[cfg(feature = "debugger")]
+pub mod gdbstub;
+pub mod hooks;
+pub mod joypad;
+pub mod movie;
+pub mod nes;
+pub mod opcode;
+pub mod rewind;
+pub mod savestate;
+pub mod snake;
+pub mod testrom;
+pub mod trace;
+#[cfg(feature = "wasm")]
+pub mod wasm;
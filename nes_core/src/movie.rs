@@ -0,0 +1,292 @@
+use crate::joypad::JoypadButton;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const FM2_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MovieStart {
+    PowerOn,
+    /// a `SaveState` in its binary form
+    SaveState(Vec<u8>),
+}
+
+/// A command of the FM2 command field, carried out before the frame's input is read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MovieCommand {
+    /// the reset button
+    SoftReset,
+    /// power cycling back to the state at power-on
+    HardReset,
+}
+
+/// Per-frame controller input for port 0, stored as an FCEUX `.fm2` movie.
+/// http://fceux.com/web/FM2.html
+///
+/// Only the first controller is recorded. Movies starting from a save state
+/// embed our own save state format, so those can only be played back here.
+/// The frontend random source is reseeded with `seed` when the movie starts;
+/// FCEUX ignores that header key.
+///
+/// Of the commands only resets are supported; movies inserting FDS disks or
+/// VS System coins are refused.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    pub rom_filename: String,
+    pub rerecord_count: u32,
+    pub seed: u64,
+    pub start: MovieStart,
+    pub frames: Vec<JoypadButton>,
+    /// by the frame they come before
+    pub commands: BTreeMap<usize, MovieCommand>,
+}
+
+impl Movie {
+    pub fn new(rom_filename: &str, start: MovieStart) -> Self {
+        Movie {
+            rom_filename: rom_filename.to_string(),
+            rerecord_count: 0,
+            seed: 0,
+            start,
+            frames: vec![],
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// Input for `frame`, or `None` once the movie is over.
+    pub fn input(&self, frame: usize) -> Option<JoypadButton> {
+        self.frames.get(frame).copied()
+    }
+
+    /// The command to carry out before `frame`.
+    pub fn command(&self, frame: usize) -> Option<MovieCommand> {
+        self.commands.get(&frame).copied()
+    }
+
+    /// Drops every frame from `frames` on, to record them again.
+    pub fn truncate(&mut self, frames: usize) {
+        self.frames.truncate(frames);
+        self.commands.split_off(&frames);
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut out = String::new();
+        out.push_str("version 3\n");
+        out.push_str("emuVersion 0\n");
+        out.push_str(&format!("rerecordCount {}\n", self.rerecord_count));
+        out.push_str("palFlag 0\n");
+        out.push_str(&format!("romFilename {}\n", self.rom_filename));
+        out.push_str("fourscore 0\n");
+        out.push_str("port0 1\n");
+        out.push_str("port1 0\n");
+        out.push_str("port2 0\n");
+        out.push_str(&format!("seed {}\n", self.seed));
+        if let MovieStart::SaveState(state) = &self.start {
+            out.push_str(&format!("savestate base64:{}\n", base64_encode(state)));
+        }
+
+        for (frame, buttons) in self.frames.iter().enumerate() {
+            let pad: String = FM2_BUTTONS
+                .iter()
+                .enumerate()
+                .map(|(i, name)| {
+                    if buttons.bits() & (0x80 >> i) != 0 {
+                        *name as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            let command = match self.command(frame) {
+                None => 0,
+                Some(MovieCommand::SoftReset) => 1,
+                Some(MovieCommand::HardReset) => 2,
+            };
+            out.push_str(&format!("|{}|{}|||\n", command, pad));
+        }
+        out
+    }
+
+    pub fn from_fm2(text: &str) -> Result<Movie, String> {
+        let mut movie = Movie::new("", MovieStart::PowerOn);
+
+        for (line_no, line) in text.lines().enumerate() {
+            if let Some(input) = line.strip_prefix('|') {
+                let fields: Vec<&str> = input.split('|').collect();
+                let pad = fields
+                    .get(1)
+                    .ok_or(format!("line {}: missing port0 input", line_no + 1))?;
+                let mut bits = 0;
+                for (i, c) in pad.bytes().take(8).enumerate() {
+                    if c != b'.' && c != b' ' {
+                        bits |= 0x80 >> i;
+                    }
+                }
+                let command = match fields[0].trim() {
+                    "" => 0,
+                    command => command
+                        .parse::<u8>()
+                        .map_err(|_| format!("line {}: bad command field", line_no + 1))?,
+                };
+                if command & !0x03 != 0 {
+                    return Err(format!("line {}: only reset commands are supported", line_no + 1));
+                }
+                if command & 0x02 != 0 {
+                    movie.commands.insert(movie.frames.len(), MovieCommand::HardReset);
+                } else if command & 0x01 != 0 {
+                    movie.commands.insert(movie.frames.len(), MovieCommand::SoftReset);
+                }
+                movie.frames.push(JoypadButton::from_bits_truncate(bits));
+                continue;
+            }
+
+            let (key, value) = match line.split_once(' ') {
+                Some((key, value)) => (key, value.trim()),
+                None => (line.trim(), ""),
+            };
+            match key {
+                "version" if value != "3" => {
+                    return Err(format!("unsupported fm2 version {}", value));
+                }
+                "rerecordCount" => {
+                    movie.rerecord_count = value
+                        .parse()
+                        .map_err(|_| format!("line {}: bad rerecordCount", line_no + 1))?;
+                }
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "seed" => {
+                    movie.seed = value
+                        .parse()
+                        .map_err(|_| format!("line {}: bad seed", line_no + 1))?;
+                }
+                "port0" if value != "1" => {
+                    return Err("only a gamepad on port0 is supported".to_string());
+                }
+                "savestate" => {
+                    let encoded = value
+                        .strip_prefix("base64:")
+                        .ok_or("only base64 savestates are supported")?;
+                    movie.start = MovieStart::SaveState(base64_decode(encoded)?);
+                }
+                _ => { /* other header keys do not affect playback */ }
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_fm2())
+    }
+
+    pub fn load_from_file(path: &Path) -> Result<Movie, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Movie::from_fm2(&text)
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut n: u32 = 0;
+    let mut bits = 0;
+    for c in text.bytes().filter(|c| *c != b'=') {
+        let value = BASE64_ALPHABET
+            .iter()
+            .position(|a| *a == c)
+            .ok_or("invalid base64 data")?;
+        n = n << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fm2_round_trip() {
+        let mut movie = Movie::new("snake", MovieStart::SaveState(vec![1, 2, 3, 4, 5]));
+        movie.rerecord_count = 3;
+        movie.seed = 0x1234_5678_9abc;
+        movie.frames.push(JoypadButton::empty());
+        movie.frames.push(JoypadButton::UP | JoypadButton::BUTTON_A);
+        movie.frames.push(JoypadButton::RIGHT | JoypadButton::START);
+
+        let text = movie.to_fm2();
+        assert!(text.contains("|0|...U...A|||\n"));
+        assert_eq!(Movie::from_fm2(&text).unwrap(), movie);
+    }
+
+    #[test]
+    fn test_import_fceux_input_log() {
+        let text = "version 3\nemuVersion 20604\nrerecordCount 12\npalFlag 0\n\
+                    romFilename smb\nguid 452DE2C3-EF43-2FA9-77AC-0677FC51543B\n\
+                    fourscore 0\nport0 1\nport1 1\nport2 0\n\
+                    |1|........|........||\n|0|R..U...A|........||\n";
+        let movie = Movie::from_fm2(text).unwrap();
+
+        assert_eq!(movie.rom_filename, "smb");
+        assert_eq!(movie.rerecord_count, 12);
+        assert_eq!(movie.seed, 0);
+        assert_eq!(movie.start, MovieStart::PowerOn);
+        assert_eq!(movie.input(0), Some(JoypadButton::empty()));
+        assert_eq!(
+            movie.input(1),
+            Some(JoypadButton::RIGHT | JoypadButton::UP | JoypadButton::BUTTON_A)
+        );
+        assert_eq!(movie.input(2), None);
+    }
+
+    #[test]
+    fn test_reset_commands() {
+        let text = "version 3\nport0 1\n|0|........|||\n|1|........|||\n|2|...U....|||\n|3|........|||\n";
+        let movie = Movie::from_fm2(text).unwrap();
+        assert_eq!(movie.command(0), None);
+        assert_eq!(movie.command(1), Some(MovieCommand::SoftReset));
+        assert_eq!(movie.command(2), Some(MovieCommand::HardReset));
+        assert_eq!(movie.command(3), Some(MovieCommand::HardReset));
+        assert_eq!(Movie::from_fm2(&movie.to_fm2()).unwrap().command(1), Some(MovieCommand::SoftReset));
+
+        let mut truncated = movie.clone();
+        truncated.truncate(2);
+        assert_eq!(truncated.frames.len(), 2);
+        assert_eq!(truncated.command(2), None);
+        assert_eq!(truncated.command(1), Some(MovieCommand::SoftReset));
+
+        // FDS disk insert
+        assert!(Movie::from_fm2("version 3\n|4|........|||\n").is_err());
+    }
+
+    #[test]
+    fn test_base64() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"\xff\x00\x80"] {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+    }
+}
@@ -0,0 +1,242 @@
+use crate::cpu::CPU;
+use crate::error::EmuError;
+use crate::joypad::JoypadButton;
+use crate::snake;
+use crate::snake::SnakeInput;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// There is no PPU to signal vblank, so a fixed number of instructions counts as one frame.
+pub const STEPS_PER_FRAME: usize = 240;
+
+/// What a frame produced. Both borrow buffers of the `Nes` that are reused every frame.
+pub struct Frame<'a> {
+    /// RGB24 pixels, row by row, `snake::SCREEN_WIDTH` by `snake::SCREEN_HEIGHT`
+    pub pixels: &'a [u8],
+    /// Mono samples in -1.0..=1.0; always empty, there is no APU yet
+    pub samples: &'a [f32],
+}
+
+/// Whatever carries the buttons, and any other outside input, into the program's memory.
+///
+/// Until there is a controller port the machine has no way of its own to read buttons,
+/// so a program that wants them brings a device, like `snake::SnakeInput`.
+pub trait InputDevice {
+    /// Called before each frame with the buttons held during it.
+    fn start_frame(&mut self, cpu: &mut CPU, buttons: JoypadButton);
+
+    /// Called before every instruction.
+    fn before_step(&mut self, _cpu: &mut CPU) {}
+}
+
+/// The machine a frontend drives one frame at a time:
+///
+/// ```ignore
+/// loop {
+///     nes.set_input(buttons);
+///     let frame = nes.run_frame()?;
+///     present(frame.pixels);
+///     queue_audio(frame.samples);
+/// }
+/// ```
+///
+/// Until there is a PPU the picture is the 32x32 screen at $0200 the snake program
+/// draws to, and input only reaches programs through an `InputDevice`.
+pub struct Nes {
+    cpu: CPU,
+    input: JoypadButton,
+    device: Option<Rc<RefCell<dyn InputDevice>>>,
+    pixels: Vec<u8>,
+    samples: Vec<f32>,
+    frame: u64,
+}
+
+impl Nes {
+    /// Wraps a CPU whose program is loaded and reset.
+    pub fn new(cpu: CPU) -> Self {
+        Nes {
+            cpu,
+            input: JoypadButton::empty(),
+            device: None,
+            pixels: vec![0; snake::SCREEN_WIDTH * snake::SCREEN_HEIGHT * 3],
+            samples: vec![],
+            frame: 0,
+        }
+    }
+
+    /// Like `new`, with `device` feeding the program its input. The caller may keep a
+    /// handle to the device, to reseed it for example.
+    pub fn with_input(cpu: CPU, device: Rc<RefCell<dyn InputDevice>>) -> Self {
+        Nes { device: Some(device), ..Nes::new(cpu) }
+    }
+
+    /// The built-in snake program; `seed` picks the sequence of apple positions.
+    pub fn snake(seed: u64) -> Self {
+        let mut cpu = CPU::new();
+        cpu.load(snake::GAME_CODE.to_vec()).expect("snake fits below the vectors");
+        cpu.reset();
+        Nes::with_input(cpu, Rc::new(RefCell::new(SnakeInput::new(seed))))
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    /// Buttons held during the following frames.
+    pub fn set_input(&mut self, buttons: JoypadButton) {
+        self.input = buttons;
+    }
+
+    /// Number of frames run so far.
+    pub fn frame_count(&self) -> u64 {
+        self.frame
+    }
+
+    /// Runs one frame and returns its picture and sound. The frame ends early when the
+    /// CPU halts, see `CPU::halt_reason`.
+    pub fn run_frame(&mut self) -> Result<Frame<'_>, EmuError> {
+        self.run_frame_with_callback(|_| {})
+    }
+
+    /// Like `run_frame`, calling `callback` before every instruction, for debuggers.
+    pub fn run_frame_with_callback<F>(&mut self, mut callback: F) -> Result<Frame<'_>, EmuError>
+    where
+        F: FnMut(&mut CPU),
+    {
+        if let Some(device) = &self.device {
+            device.borrow_mut().start_frame(&mut self.cpu, self.input);
+        }
+        for _ in 0..STEPS_PER_FRAME {
+            if let Some(device) = &self.device {
+                device.borrow_mut().before_step(&mut self.cpu);
+            }
+            callback(&mut self.cpu);
+            if !self.cpu.step()? {
+                break;
+            }
+        }
+        self.frame += 1;
+
+        snake::render(&self.cpu, &mut self.pixels);
+        self.samples.clear();
+        Ok(Frame { pixels: &self.pixels, samples: &self.samples })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::HaltReason;
+    use crate::cpu::Mem;
+    use crate::savestate::SaveState;
+    use crate::savestate::Thumbnail;
+
+    #[test]
+    fn test_run_snake_frames() {
+        let mut nes = Nes::snake(42);
+        let frame = nes.run_frame().unwrap();
+        assert_eq!(frame.pixels.len(), 32 * 32 * 3);
+        assert!(frame.samples.is_empty());
+        // the snake's head and the apple
+        assert!(frame.pixels.chunks(3).any(|pixel| pixel == [255, 255, 255]));
+        assert!(frame.pixels.chunks(3).filter(|pixel| *pixel != [0, 0, 0]).count() >= 2);
+
+        nes.set_input(JoypadButton::DOWN | JoypadButton::BUTTON_A);
+        nes.run_frame().unwrap();
+        assert_eq!(nes.cpu().mem_peek(0xff), b's');
+        assert_eq!(nes.frame_count(), 2);
+    }
+
+    #[test]
+    fn test_same_seed_same_frames() {
+        let (mut first, mut second) = (Nes::snake(7), Nes::snake(7));
+        for _ in 0..100 {
+            let pixels = first.run_frame().unwrap().pixels.to_vec();
+            assert_eq!(pixels, second.run_frame().unwrap().pixels);
+        }
+    }
+
+    fn memory(nes: &Nes) -> Vec<u8> {
+        (0..=0xffff).map(|addr| nes.cpu().mem_peek(addr)).collect()
+    }
+
+    #[test]
+    fn test_reseed_repeats_frames() {
+        let mut cpu = CPU::new();
+        cpu.load(snake::GAME_CODE.to_vec()).unwrap();
+        cpu.reset();
+        let input = Rc::new(RefCell::new(SnakeInput::new(7)));
+        let mut nes = Nes::with_input(cpu, input.clone());
+        nes.run_frame().unwrap();
+        let state = SaveState::capture(nes.cpu(), 0, Thumbnail { width: 0, height: 0, pixels: vec![] });
+        let random = input.borrow().random_state();
+        nes.run_frame().unwrap();
+        let after = memory(&nes);
+
+        state.restore(nes.cpu_mut()).unwrap();
+        input.borrow_mut().set_seed(random);
+        nes.run_frame().unwrap();
+        assert_eq!(memory(&nes), after);
+    }
+
+    #[test]
+    fn test_callback_before_every_instruction() {
+        let mut nes = Nes::snake(1);
+        let mut calls = 0;
+        nes.run_frame_with_callback(|_| calls += 1).unwrap();
+        assert_eq!(calls, STEPS_PER_FRAME);
+    }
+
+    /// Counts frames at $10 and instructions at $11.
+    struct CountingDevice;
+
+    impl InputDevice for CountingDevice {
+        fn start_frame(&mut self, cpu: &mut CPU, buttons: JoypadButton) {
+            cpu.mem_write(0x10, cpu.mem_peek(0x10) + 1);
+            cpu.mem_write(0x12, buttons.bits());
+        }
+
+        fn before_step(&mut self, cpu: &mut CPU) {
+            cpu.mem_write(0x11, cpu.mem_peek(0x11).wrapping_add(1));
+        }
+    }
+
+    #[test]
+    fn test_input_device() {
+        let mut cpu = CPU::new();
+        // loop: JMP loop
+        cpu.load(vec![0x4c, 0x00, 0x06]).unwrap();
+        cpu.reset();
+        let mut nes = Nes::with_input(cpu, Rc::new(RefCell::new(CountingDevice)));
+        nes.set_input(JoypadButton::START);
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+
+        assert_eq!(nes.cpu().mem_peek(0x10), 2);
+        assert_eq!(nes.cpu().mem_peek(0x11), (2 * STEPS_PER_FRAME) as u8);
+        assert_eq!(nes.cpu().mem_peek(0x12), JoypadButton::START.bits());
+    }
+
+    #[test]
+    fn test_frame_ends_at_halt() {
+        let mut cpu = CPU::new();
+        // LDA #$01 / STA $0200 / BRK
+        cpu.load(vec![0xa9, 0x01, 0x8d, 0x00, 0x02, 0x00]).unwrap();
+        cpu.reset();
+        let mut nes = Nes::new(cpu);
+
+        assert_eq!(&nes.run_frame().unwrap().pixels[..6], &[255, 255, 255, 0, 0, 0]);
+        assert_eq!(nes.cpu().halt_reason(), Some(HaltReason::Break { pc: 0x0605 }));
+        // nothing wrote the snake's $FE or $FF without its input device
+        assert_eq!(nes.cpu().mem_peek(0xfe), 0);
+        assert_eq!(nes.cpu().mem_peek(0xff), 0);
+
+        nes.cpu_mut().mem_write(0x0600, 0x03);
+        nes.cpu_mut().program_counter = 0x0600;
+        assert_eq!(nes.run_frame().err(), Some(EmuError::UnknownOpcode { opcode: 0x03, pc: 0x0600 }));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::cpu::AddressingMode;
+use crate::cpu::CpuVariant;
 use std::collections::HashMap;
 
 pub struct OpCode {
@@ -12,11 +13,11 @@ pub struct OpCode {
 impl OpCode {
     fn new(code: u8, mnemonic: &'static str, len: u8, cycles: u8, mode: AddressingMode) -> Self {
         OpCode {
-            code: code,
-            mnemonic: mnemonic,
-            len: len,
-            cycles: cycles,
-            mode: mode,
+            code,
+            mnemonic,
+            len,
+            cycles,
+            mode,
         }
     }
 }
@@ -220,6 +221,74 @@ lazy_static! {
     ];
 
 
+    /// What the 65C02 adds to or changes in the NMOS instruction set.
+    /// http://6502.org/tutorials/65c02opcodes.html
+    pub static ref CMOS_OPS_CODES: Vec<OpCode> = vec![
+        OpCode::new(0x80, "BRA", 2, 2 /*+1 as it always branches, +1 if to a new page*/, AddressingMode::NoneAddressing),
+        OpCode::new(0x6c, "JMP", 3, 6, AddressingMode::NoneAddressing), //AddressingMode:Indirect, without the page bug
+        OpCode::new(0x7c, "JMP", 3, 6, AddressingMode::NoneAddressing), //AddressingMode:Indexed indirect, (abs,X)
+
+        OpCode::new(0x89, "BIT", 2, 2, AddressingMode::Immediate),
+        OpCode::new(0x34, "BIT", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new(0x3c, "BIT", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+
+        OpCode::new(0xda, "PHX", 1, 3, AddressingMode::NoneAddressing),
+        OpCode::new(0x5a, "PHY", 1, 3, AddressingMode::NoneAddressing),
+        OpCode::new(0xfa, "PLX", 1, 4, AddressingMode::NoneAddressing),
+        OpCode::new(0x7a, "PLY", 1, 4, AddressingMode::NoneAddressing),
+
+        OpCode::new(0x64, "STZ", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new(0x74, "STZ", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new(0x9c, "STZ", 3, 4, AddressingMode::Absolute),
+        OpCode::new(0x9e, "STZ", 3, 5, AddressingMode::Absolute_X),
+
+        OpCode::new(0x14, "TRB", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x1c, "TRB", 3, 6, AddressingMode::Absolute),
+        OpCode::new(0x04, "TSB", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x0c, "TSB", 3, 6, AddressingMode::Absolute),
+
+        OpCode::new(0x1a, "INC", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x3a, "DEC", 1, 2, AddressingMode::NoneAddressing),
+
+        OpCode::new(0x12, "ORA", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0x32, "AND", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0x52, "EOR", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0x72, "ADC", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0x92, "STA", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0xb2, "LDA", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0xd2, "CMP", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0xf2, "SBC", 2, 5, AddressingMode::ZeroPage_Indirect),
+    ];
+
+    /// The opcodes the 65C02 leaves undefined. They are NOPs that skip their operand
+    /// bytes; the columns $x3, $x7, $xB and $xF are single-cycle NOPs.
+    /// http://www.6502.org/tutorials/65c02opcodes.html#10
+    pub static ref CMOS_NOP_OPS_CODES: Vec<OpCode> = {
+        let mut ops = vec![
+            OpCode::new(0x02, "NOP", 2, 2, AddressingMode::Immediate),
+            OpCode::new(0x22, "NOP", 2, 2, AddressingMode::Immediate),
+            OpCode::new(0x42, "NOP", 2, 2, AddressingMode::Immediate),
+            OpCode::new(0x62, "NOP", 2, 2, AddressingMode::Immediate),
+            OpCode::new(0x82, "NOP", 2, 2, AddressingMode::Immediate),
+            OpCode::new(0xc2, "NOP", 2, 2, AddressingMode::Immediate),
+            OpCode::new(0xe2, "NOP", 2, 2, AddressingMode::Immediate),
+
+            OpCode::new(0x44, "NOP", 2, 3, AddressingMode::ZeroPage),
+            OpCode::new(0x54, "NOP", 2, 4, AddressingMode::ZeroPage_X),
+            OpCode::new(0xd4, "NOP", 2, 4, AddressingMode::ZeroPage_X),
+            OpCode::new(0xf4, "NOP", 2, 4, AddressingMode::ZeroPage_X),
+
+            OpCode::new(0x5c, "NOP", 3, 8, AddressingMode::Absolute),
+            OpCode::new(0xdc, "NOP", 3, 4, AddressingMode::Absolute),
+            OpCode::new(0xfc, "NOP", 3, 4, AddressingMode::Absolute),
+        ];
+        for code in (0x03..=0xff).step_by(4) {
+            ops.push(OpCode::new(code, "NOP", 1, 1, AddressingMode::NoneAddressing));
+        }
+        ops
+    };
+
+
     pub static ref OPCODES_MAP: HashMap<u8, &'static OpCode> = {
         let mut map = HashMap::new();
         for cpuop in &*CPU_OPS_CODES {
@@ -227,4 +296,41 @@ lazy_static! {
         }
         map
     };
-}
\ No newline at end of file
+
+    pub static ref CMOS_OPCODES_MAP: HashMap<u8, &'static OpCode> = {
+        let mut map = HashMap::new();
+        for cpuop in CPU_OPS_CODES.iter().chain(&*CMOS_OPS_CODES).chain(&*CMOS_NOP_OPS_CODES) {
+            map.insert(cpuop.code, cpuop);
+        }
+        map
+    };
+}
+
+/// The documented instructions of `variant`, without the NOPs of the 65C02's undefined
+/// opcodes.
+pub fn instructions(variant: CpuVariant) -> impl Iterator<Item = &'static OpCode> {
+    let cmos: &'static [OpCode] = match variant {
+        CpuVariant::Ricoh2A03 | CpuVariant::Nmos6502 => &[],
+        CpuVariant::Cmos65C02 => &CMOS_OPS_CODES,
+    };
+    CPU_OPS_CODES.iter().chain(cmos)
+}
+
+/// The instruction set of `variant`, by opcode.
+pub fn opcodes_map(variant: CpuVariant) -> &'static HashMap<u8, &'static OpCode> {
+    match variant {
+        CpuVariant::Ricoh2A03 | CpuVariant::Nmos6502 => &OPCODES_MAP,
+        CpuVariant::Cmos65C02 => &CMOS_OPCODES_MAP,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_65c02_defines_every_opcode() {
+        assert_eq!(CMOS_OPCODES_MAP.len(), 256);
+        assert_eq!(CPU_OPS_CODES.len() + CMOS_OPS_CODES.len() + CMOS_NOP_OPS_CODES.len() - 1, 256);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::cpu::CPU;
+use crate::savestate::SaveState;
+use crate::savestate::Thumbnail;
+use std::collections::VecDeque;
+
+/// Ring buffer of machine snapshots for rewinding.
+///
+/// Only the newest snapshot is kept whole; every older one is stored as the
+/// run-length encoded XOR against its successor, so consecutive frames that
+/// barely differ cost a few bytes. When the buffer grows past its memory
+/// budget the oldest deltas are dropped.
+pub struct Rewind {
+    interval: usize,
+    budget: usize,
+    frames: usize,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    deltas_size: usize,
+}
+
+impl Rewind {
+    /// Takes a snapshot every `interval` frames, using at most `budget` bytes.
+    pub fn new(interval: usize, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frames: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+        }
+    }
+
+    /// Call once per emulated frame.
+    pub fn on_frame(&mut self, cpu: &CPU) {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+
+        let snapshot = snapshot(cpu);
+        if let Some(previous) = self.newest.take() {
+            let delta = rle_encode(&xor(&previous, &snapshot));
+            self.deltas_size += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.newest = Some(snapshot);
+
+        while self.memory_used() > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Restores the newest snapshot and makes the one before it the newest.
+    /// Returns `false` when there is nothing to rewind to; a snapshot that fails to
+    /// restore is dropped all the same.
+    pub fn rewind(&mut self, cpu: &mut CPU) -> Result<bool, String> {
+        let newest = match self.newest.take() {
+            Some(newest) => newest,
+            None => return Ok(false),
+        };
+        let restored = SaveState::from_bytes(&newest).and_then(|state| state.restore(cpu));
+
+        self.newest = match self.deltas.pop_back() {
+            Some(delta) => {
+                self.deltas_size -= delta.len();
+                Some(xor(&newest, &rle_decode(&delta)))
+            }
+            // the oldest snapshot stays around so holding the key sits on it
+            None if restored.is_ok() => Some(newest),
+            None => None,
+        };
+        self.frames = 0;
+        restored.map(|()| true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.deltas_size + self.newest.as_ref().map_or(0, |newest| newest.len())
+    }
+}
+
+fn snapshot(cpu: &CPU) -> Vec<u8> {
+    let thumbnail = Thumbnail { width: 0, height: 0, pixels: vec![] };
+    SaveState::capture(cpu, 0, thumbnail).to_bytes()
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a ^ b).collect()
+}
+
+/// Encodes runs as (length u16 LE, byte) triples.
+fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let byte = data[i];
+        let mut run = 1;
+        while i + run < data.len() && data[i + run] == byte && run < u16::MAX as usize {
+            run += 1;
+        }
+        out.extend_from_slice(&(run as u16).to_le_bytes());
+        out.push(byte);
+        i += run;
+    }
+    out
+}
+
+fn rle_decode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for chunk in data.chunks_exact(3) {
+        let run = u16::from_le_bytes([chunk[0], chunk[1]]) as usize;
+        out.extend(std::iter::repeat_n(chunk[2], run));
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_rle_round_trip() {
+        let mut data = vec![0; 70_000];
+        data[5] = 1;
+        data[6] = 2;
+        data[69_999] = 3;
+
+        let encoded = rle_encode(&data);
+        assert!(encoded.len() < 20);
+        assert_eq!(rle_decode(&encoded), data);
+    }
+
+    #[test]
+    fn test_rewind_walks_back_through_snapshots() {
+        let mut cpu = CPU::new();
+        let mut rewind = Rewind::new(1, usize::MAX);
+        for frame in 0..5 {
+            cpu.mem_write(0x10, frame);
+            rewind.on_frame(&cpu);
+        }
+
+        for frame in (0..5).rev() {
+            assert_eq!(rewind.rewind(&mut cpu), Ok(true));
+            assert_eq!(cpu.mem_read(0x10), frame);
+        }
+        assert_eq!(rewind.rewind(&mut cpu), Ok(true));
+        assert_eq!(cpu.mem_read(0x10), 0);
+    }
+
+    #[test]
+    fn test_bad_snapshot_is_reported_and_dropped() {
+        let mut cpu = CPU::new();
+        let mut rewind = Rewind::new(1, usize::MAX);
+        assert_eq!(rewind.rewind(&mut cpu), Ok(false));
+
+        rewind.on_frame(&cpu);
+        rewind.newest.as_mut().unwrap().truncate(8);
+        assert!(rewind.rewind(&mut cpu).is_err());
+        assert_eq!(rewind.rewind(&mut cpu), Ok(false));
+    }
+
+    #[test]
+    fn test_budget_drops_oldest_snapshots() {
+        let mut cpu = CPU::new();
+        let mut rewind = Rewind::new(1, 0x10100);
+        for frame in 0..100 {
+            cpu.mem_write(frame, 0xff);
+            rewind.on_frame(&cpu);
+        }
+
+        assert!(rewind.memory_used() <= 0x10100);
+        assert!(rewind.len() < 100);
+    }
+}
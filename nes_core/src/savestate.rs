@@ -0,0 +1,431 @@
+use crate::cpu::CpuFlags;
+use crate::cpu::CpuVariant;
+use crate::cpu::HaltReason;
+use crate::cpu::Interrupt;
+use crate::cpu::CPU;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const MAGIC: [u8; 4] = *b"NESS";
+pub const VERSION: u16 = 3;
+
+/// Hash identifying the program a state belongs to (32-bit FNV-1a).
+pub fn rom_hash(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-savestates", derive(serde::Serialize, serde::Deserialize))]
+pub struct Thumbnail {
+    pub width: u16,
+    pub height: u16,
+    /// RGB24 pixels, row by row
+    pub pixels: Vec<u8>,
+}
+
+/// Snapshot of the whole machine.
+///
+/// Layout (little endian):
+///
+///  magic "NESS" | version u16 | rom hash u32 | thumbnail w u16, h u16, w*h*3 bytes |
+///  A | X | Y | P | SP | PC u16 | cycles u64 | variant u8 | settings u8 |
+///  halt: kind u8, pc u16, opcode u8 | NMI edge: set u8, cycle u64 |
+///  IRQ line: set u8, cycle u64 | pending interrupt u8 | 64KB address space
+///
+/// The interrupt lines and the halt are kept, so a state taken between an interrupt's
+/// poll and its handler, or while jammed, resumes exactly where it was. The settings
+/// byte holds `cycle_accurate_bus`, `break_halts` and `detect_infinite_loops` in bits
+/// 0-2, which change how the same program runs. A state only restores into a CPU of
+/// the variant it was taken from.
+///
+/// The machine is a CPU with a flat address space, so RAM, PRG-RAM and the
+/// controller latch all live in `memory`; there is no PPU, APU or mapper
+/// state to save yet.
+///
+/// With the `serde-savestates` feature it also implements `Serialize` and `Deserialize`,
+/// for storing states in other formats than the one above.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde-savestates", derive(serde::Serialize, serde::Deserialize))]
+pub struct SaveState {
+    pub rom_hash: u32,
+    pub thumbnail: Thumbnail,
+    register_a: u8,
+    register_x: u8,
+    register_y: u8,
+    status: u8,
+    stack_pointer: u8,
+    program_counter: u16,
+    cycles: u64,
+    variant: CpuVariant,
+    cycle_accurate_bus: bool,
+    break_halts: bool,
+    detect_infinite_loops: bool,
+    halt: Option<HaltReason>,
+    nmi_edge: Option<u64>,
+    irq_line: Option<u64>,
+    pending_interrupt: Option<Interrupt>,
+    memory: Vec<u8>,
+}
+
+impl SaveState {
+    pub fn capture(cpu: &CPU, rom_hash: u32, thumbnail: Thumbnail) -> Self {
+        SaveState {
+            rom_hash,
+            thumbnail,
+            register_a: cpu.register_a,
+            register_x: cpu.register_x,
+            register_y: cpu.register_y,
+            status: cpu.status.bits(),
+            stack_pointer: cpu.stack_pointer,
+            program_counter: cpu.program_counter,
+            cycles: cpu.cycles as u64,
+            variant: cpu.variant(),
+            cycle_accurate_bus: cpu.cycle_accurate_bus,
+            break_halts: cpu.break_halts,
+            detect_infinite_loops: cpu.detect_infinite_loops,
+            halt: cpu.halt,
+            nmi_edge: cpu.nmi_edge.map(|cycle| cycle as u64),
+            irq_line: cpu.irq_line.map(|cycle| cycle as u64),
+            pending_interrupt: cpu.pending_interrupt,
+            memory: cpu.memory.to_vec(),
+        }
+    }
+
+    /// Fails, leaving `cpu` alone, when it is another variant than the one the state was
+    /// taken from, or when the state is malformed, as one deserialized through serde can be.
+    pub fn restore(&self, cpu: &mut CPU) -> Result<(), String> {
+        if cpu.variant() != self.variant {
+            return Err(format!("state was saved from a {:?}, not a {:?}", self.variant, cpu.variant()));
+        }
+        if self.memory.len() != cpu.memory.len() {
+            return Err(format!("state holds {} bytes of memory, not {}", self.memory.len(), cpu.memory.len()));
+        }
+        let Thumbnail { width, height, pixels } = &self.thumbnail;
+        if pixels.len() != *width as usize * *height as usize * 3 {
+            return Err(format!("thumbnail has {} bytes for {}x{} pixels", pixels.len(), width, height));
+        }
+        cpu.register_a = self.register_a;
+        cpu.register_x = self.register_x;
+        cpu.register_y = self.register_y;
+        cpu.status = CpuFlags::from_bits_truncate(self.status);
+        cpu.stack_pointer = self.stack_pointer;
+        cpu.program_counter = self.program_counter;
+        cpu.cycles = self.cycles as usize;
+        cpu.cycle_accurate_bus = self.cycle_accurate_bus;
+        cpu.break_halts = self.break_halts;
+        cpu.detect_infinite_loops = self.detect_infinite_loops;
+        cpu.halt = self.halt;
+        cpu.nmi_edge = self.nmi_edge.map(|cycle| cycle as usize);
+        cpu.irq_line = self.irq_line.map(|cycle| cycle as usize);
+        cpu.pending_interrupt = self.pending_interrupt;
+        cpu.memory.copy_from_slice(&self.memory);
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + self.thumbnail.pixels.len() + self.memory.len());
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&self.rom_hash.to_le_bytes());
+        out.extend_from_slice(&self.thumbnail.width.to_le_bytes());
+        out.extend_from_slice(&self.thumbnail.height.to_le_bytes());
+        out.extend_from_slice(&self.thumbnail.pixels);
+        out.extend_from_slice(&[
+            self.register_a,
+            self.register_x,
+            self.register_y,
+            self.status,
+            self.stack_pointer,
+        ]);
+        out.extend_from_slice(&self.program_counter.to_le_bytes());
+        out.extend_from_slice(&self.cycles.to_le_bytes());
+        out.push(match self.variant {
+            CpuVariant::Ricoh2A03 => 0,
+            CpuVariant::Nmos6502 => 1,
+            CpuVariant::Cmos65C02 => 2,
+        });
+        out.push(
+            self.cycle_accurate_bus as u8 | (self.break_halts as u8) << 1 | (self.detect_infinite_loops as u8) << 2,
+        );
+        let (kind, pc, opcode) = match self.halt {
+            None => (0, 0, 0),
+            Some(HaltReason::Break { pc }) => (1, pc, 0),
+            Some(HaltReason::Jam { opcode, pc }) => (2, pc, opcode),
+            Some(HaltReason::InfiniteLoop { pc }) => (3, pc, 0),
+        };
+        out.push(kind);
+        out.extend_from_slice(&pc.to_le_bytes());
+        out.push(opcode);
+        for line in [self.nmi_edge, self.irq_line] {
+            out.push(line.is_some() as u8);
+            out.extend_from_slice(&line.unwrap_or(0).to_le_bytes());
+        }
+        out.push(match self.pending_interrupt {
+            None => 0,
+            Some(Interrupt::Nmi) => 1,
+            Some(Interrupt::Irq) => 2,
+            Some(Interrupt::Brk) => 3,
+        });
+        out.extend_from_slice(&self.memory);
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<SaveState, String> {
+        let mut reader = Reader { data, pos: 0 };
+
+        if reader.take(4)? != MAGIC {
+            return Err("not a save state".to_string());
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(format!("unsupported save state version {}", version));
+        }
+        let rom_hash = reader.u32()?;
+        let width = reader.u16()?;
+        let height = reader.u16()?;
+        let pixels = reader.take(width as usize * height as usize * 3)?.to_vec();
+        let registers = reader.take(5)?;
+        let (register_a, register_x, register_y, status, stack_pointer) =
+            (registers[0], registers[1], registers[2], registers[3], registers[4]);
+        let program_counter = reader.u16()?;
+        let cycles = reader.u64()?;
+        let variant = match reader.u8()? {
+            0 => CpuVariant::Ricoh2A03,
+            1 => CpuVariant::Nmos6502,
+            2 => CpuVariant::Cmos65C02,
+            other => return Err(format!("unknown CPU variant {}", other)),
+        };
+        let settings = reader.u8()?;
+        let kind = reader.u8()?;
+        let pc = reader.u16()?;
+        let opcode = reader.u8()?;
+        let halt = match kind {
+            0 => None,
+            1 => Some(HaltReason::Break { pc }),
+            2 => Some(HaltReason::Jam { opcode, pc }),
+            3 => Some(HaltReason::InfiniteLoop { pc }),
+            other => return Err(format!("unknown halt reason {}", other)),
+        };
+        let nmi_edge = reader.line()?;
+        let irq_line = reader.line()?;
+        let pending_interrupt = match reader.u8()? {
+            0 => None,
+            1 => Some(Interrupt::Nmi),
+            2 => Some(Interrupt::Irq),
+            3 => Some(Interrupt::Brk),
+            other => return Err(format!("unknown interrupt {}", other)),
+        };
+        let memory = reader.take(0x10000)?.to_vec();
+
+        Ok(SaveState {
+            rom_hash,
+            thumbnail: Thumbnail { width, height, pixels },
+            register_a,
+            register_x,
+            register_y,
+            status,
+            stack_pointer,
+            program_counter,
+            cycles,
+            variant,
+            cycle_accurate_bus: settings & 1 != 0,
+            break_halts: settings & 2 != 0,
+            detect_infinite_loops: settings & 4 != 0,
+            halt,
+            nmi_edge,
+            irq_line,
+            pending_interrupt,
+            memory,
+        })
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    /// Reads a state from disk, refusing states taken with a different program.
+    pub fn load_from_file(path: &Path, rom_hash: u32) -> Result<SaveState, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let state = SaveState::from_bytes(&data)?;
+        if state.rom_hash != rom_hash {
+            return Err(format!("{} was saved from another ROM", path.display()));
+        }
+        Ok(state)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.pos + len > self.data.len() {
+            return Err("save state is truncated".to_string());
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// An interrupt line: whether it is set, then the cycle it was set at.
+    fn line(&mut self) -> Result<Option<u64>, String> {
+        let set = self.u8()? != 0;
+        let cycle = self.u64()?;
+        Ok(set.then_some(cycle))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Mem;
+
+    fn thumbnail() -> Thumbnail {
+        Thumbnail {
+            width: 2,
+            height: 1,
+            pixels: vec![1, 2, 3, 4, 5, 6],
+        }
+    }
+
+    #[test]
+    fn test_round_trip_into_fresh_cpu() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xa9, 0x80, 0xa2, 0x07, 0x85, 0x10, 0x38, 0x00]).unwrap();
+
+        let bytes = SaveState::capture(&cpu, 0xdead_beef, thumbnail()).to_bytes();
+        let state = SaveState::from_bytes(&bytes).unwrap();
+        assert_eq!(state.rom_hash, 0xdead_beef);
+        assert_eq!(state.thumbnail, thumbnail());
+
+        let mut restored = CPU::new();
+        state.restore(&mut restored).unwrap();
+        assert_eq!(restored.register_a, 0x80);
+        assert_eq!(restored.register_x, 0x07);
+        assert_eq!(restored.program_counter, cpu.program_counter);
+        assert_eq!(restored.status, cpu.status);
+        assert_eq!(restored.cycles, cpu.cycles);
+        assert_eq!(restored.mem_read(0x10), 0x80);
+    }
+
+    #[test]
+    fn test_round_trip_with_pending_interrupt() {
+        let mut cpu = CPU::new();
+        // NOP / NOP, NMI handler at $0700
+        cpu.load(vec![0xea, 0xea]).unwrap();
+        cpu.reset();
+        cpu.mem_write_u16(0xfffa, 0x0700);
+        cpu.set_irq(true);
+        cpu.raise_nmi();
+        // the NOP polls the NMI, which is taken before the next instruction
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0601);
+
+        let bytes = SaveState::capture(&cpu, 0, thumbnail()).to_bytes();
+        let mut restored = CPU::new();
+        SaveState::from_bytes(&bytes).unwrap().restore(&mut restored).unwrap();
+        restored.step().unwrap();
+        assert_eq!(restored.program_counter, 0x0700);
+        assert_eq!(restored.cycles, cpu.cycles + 7);
+        // the IRQ line is still asserted, but the handler runs with I set
+        assert_eq!(restored.irq_line, cpu.irq_line);
+        assert_eq!(restored.nmi_edge, None);
+    }
+
+    #[test]
+    fn test_round_trip_keeps_halt() {
+        let mut cpu = CPU::new();
+        // KIL
+        cpu.load(vec![0x02]).unwrap();
+        cpu.reset();
+        assert!(!cpu.step().unwrap());
+
+        let bytes = SaveState::capture(&cpu, 0, thumbnail()).to_bytes();
+        let mut restored = CPU::new();
+        SaveState::from_bytes(&bytes).unwrap().restore(&mut restored).unwrap();
+        assert_eq!(restored.halt_reason(), Some(HaltReason::Jam { opcode: 0x02, pc: 0x0600 }));
+        assert!(!restored.step().unwrap());
+    }
+
+    #[test]
+    fn test_round_trip_keeps_settings() {
+        let mut cpu = CPU::with_variant(CpuVariant::Cmos65C02);
+        cpu.cycle_accurate_bus = true;
+        cpu.break_halts = false;
+        cpu.detect_infinite_loops = true;
+
+        let state = SaveState::from_bytes(&SaveState::capture(&cpu, 0, thumbnail()).to_bytes()).unwrap();
+        let mut restored = CPU::with_variant(CpuVariant::Cmos65C02);
+        state.restore(&mut restored).unwrap();
+        assert!(restored.cycle_accurate_bus);
+        assert!(!restored.break_halts);
+        assert!(restored.detect_infinite_loops);
+    }
+
+    #[cfg(feature = "serde-savestates")]
+    #[test]
+    fn test_reject_malformed_deserialized_state() {
+        let mut cpu = CPU::new();
+        let state = serde_json::to_value(SaveState::capture(&cpu, 0, thumbnail())).unwrap();
+        let restored: SaveState = serde_json::from_value(state.clone()).unwrap();
+        assert_eq!(restored.restore(&mut cpu), Ok(()));
+
+        let mut short_memory = state.clone();
+        short_memory["memory"].as_array_mut().unwrap().truncate(0x800);
+        let short_memory: SaveState = serde_json::from_value(short_memory).unwrap();
+        assert_eq!(
+            short_memory.restore(&mut cpu).unwrap_err(),
+            "state holds 2048 bytes of memory, not 65536"
+        );
+
+        let mut wide_thumbnail = state;
+        wide_thumbnail["thumbnail"]["width"] = 3.into();
+        let wide_thumbnail: SaveState = serde_json::from_value(wide_thumbnail).unwrap();
+        assert!(wide_thumbnail.restore(&mut cpu).is_err());
+    }
+
+    #[test]
+    fn test_reject_other_variant() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xa9, 0x80, 0x00]).unwrap();
+        let state = SaveState::capture(&cpu, 0, thumbnail());
+
+        let mut other = CPU::with_variant(CpuVariant::Nmos6502);
+        assert!(state.restore(&mut other).is_err());
+        assert_eq!(other.register_a, 0);
+    }
+
+    #[test]
+    fn test_reject_other_versions_and_truncated_data() {
+        let mut bytes = SaveState::capture(&CPU::new(), 0, thumbnail()).to_bytes();
+        assert!(SaveState::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        bytes[4] = 0xff;
+        assert!(SaveState::from_bytes(&bytes).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::joypad::JoypadButton;
+use crate::nes::InputDevice;
+
+/// The snake game from Nick Morgan's "Easy 6502" tutorial, assembled for $0600.
+///
+/// It reads a random byte from $FE and the last pressed key, as ascii (w, a, s, d), from
+/// $FF, and draws to a 32x32 screen of one byte per pixel at $0200-$05FF.
+pub const GAME_CODE: [u8; 309] = [
+    0x20, 0x06, 0x06, 0x20, 0x38, 0x06, 0x20, 0x0d, 0x06, 0x20, 0x2a, 0x06, 0x60, 0xa9, 0x02,
+    0x85, 0x02, 0xa9, 0x04, 0x85, 0x03, 0xa9, 0x11, 0x85, 0x10, 0xa9, 0x10, 0x85, 0x12, 0xa9,
+    0x0f, 0x85, 0x14, 0xa9, 0x04, 0x85, 0x11, 0x85, 0x13, 0x85, 0x15, 0x60, 0xa5, 0xfe, 0x85,
+    0x00, 0xa5, 0xfe, 0x29, 0x03, 0x18, 0x69, 0x02, 0x85, 0x01, 0x60, 0x20, 0x4d, 0x06, 0x20,
+    0x8d, 0x06, 0x20, 0xc3, 0x06, 0x20, 0x19, 0x07, 0x20, 0x20, 0x07, 0x20, 0x2d, 0x07, 0x4c,
+    0x38, 0x06, 0xa5, 0xff, 0xc9, 0x77, 0xf0, 0x0d, 0xc9, 0x64, 0xf0, 0x14, 0xc9, 0x73, 0xf0,
+    0x1b, 0xc9, 0x61, 0xf0, 0x22, 0x60, 0xa9, 0x04, 0x24, 0x02, 0xd0, 0x26, 0xa9, 0x01, 0x85,
+    0x02, 0x60, 0xa9, 0x08, 0x24, 0x02, 0xd0, 0x1b, 0xa9, 0x02, 0x85, 0x02, 0x60, 0xa9, 0x01,
+    0x24, 0x02, 0xd0, 0x10, 0xa9, 0x04, 0x85, 0x02, 0x60, 0xa9, 0x02, 0x24, 0x02, 0xd0, 0x05,
+    0xa9, 0x08, 0x85, 0x02, 0x60, 0x60, 0x20, 0x94, 0x06, 0x20, 0xa8, 0x06, 0x60, 0xa5, 0x00,
+    0xc5, 0x10, 0xd0, 0x0d, 0xa5, 0x01, 0xc5, 0x11, 0xd0, 0x07, 0xe6, 0x03, 0xe6, 0x03, 0x20,
+    0x2a, 0x06, 0x60, 0xa2, 0x02, 0xb5, 0x10, 0xc5, 0x10, 0xd0, 0x06, 0xb5, 0x11, 0xc5, 0x11,
+    0xf0, 0x09, 0xe8, 0xe8, 0xe4, 0x03, 0xf0, 0x06, 0x4c, 0xaa, 0x06, 0x4c, 0x35, 0x07, 0x60,
+    0xa6, 0x03, 0xca, 0x8a, 0xb5, 0x10, 0x95, 0x12, 0xca, 0x10, 0xf9, 0xa5, 0x02, 0x4a, 0xb0,
+    0x09, 0x4a, 0xb0, 0x19, 0x4a, 0xb0, 0x1f, 0x4a, 0xb0, 0x2f, 0xa5, 0x10, 0x38, 0xe9, 0x20,
+    0x85, 0x10, 0x90, 0x01, 0x60, 0xc6, 0x11, 0xa9, 0x01, 0xc5, 0x11, 0xf0, 0x28, 0x60, 0xe6,
+    0x10, 0xa9, 0x1f, 0x24, 0x10, 0xf0, 0x1f, 0x60, 0xa5, 0x10, 0x18, 0x69, 0x20, 0x85, 0x10,
+    0xb0, 0x01, 0x60, 0xe6, 0x11, 0xa9, 0x06, 0xc5, 0x11, 0xf0, 0x0c, 0x60, 0xc6, 0x10, 0xa5,
+    0x10, 0x29, 0x1f, 0xc9, 0x1f, 0xf0, 0x01, 0x60, 0x4c, 0x35, 0x07, 0xa0, 0x00, 0xa5, 0xfe,
+    0x91, 0x00, 0x60, 0xa6, 0x03, 0xa9, 0x00, 0x81, 0x10, 0xa2, 0x00, 0xa9, 0x01, 0x81, 0x10,
+    0x60, 0xa6, 0xff, 0xea, 0xea, 0xca, 0xd0, 0xfb, 0x60,
+];
+
+/// First byte of the screen, row by row.
+pub const SCREEN_START: u16 = 0x0200;
+pub const SCREEN_WIDTH: usize = 32;
+pub const SCREEN_HEIGHT: usize = 32;
+
+/// RGB of a screen byte, in the palette of the tutorial's simulator.
+pub fn color(byte: u8) -> [u8; 3] {
+    match byte {
+        0 => [0, 0, 0],
+        1 => [255, 255, 255],
+        2 | 9 => [128, 128, 128],
+        3 | 10 => [255, 0, 0],
+        4 | 11 => [0, 255, 0],
+        5 | 12 => [0, 0, 255],
+        6 | 13 => [255, 0, 255],
+        7 | 14 => [255, 255, 0],
+        _ => [0, 255, 255],
+    }
+}
+
+/// Draws the screen into RGB24 `pixels`, row by row. Returns whether any pixel changed.
+pub fn render(cpu: &CPU, pixels: &mut [u8]) -> bool {
+    let mut update = false;
+    for (i, pixel) in pixels.chunks_exact_mut(3).take(SCREEN_WIDTH * SCREEN_HEIGHT).enumerate() {
+        let rgb = color(cpu.mem_peek(SCREEN_START + i as u16));
+        if pixel != rgb {
+            pixel.copy_from_slice(&rgb);
+            update = true;
+        }
+    }
+    update
+}
+
+/// Feeds the snake program the way it reads its input: the direction held goes to $FF,
+/// see `write_input`, and before every instruction $FE gets a random byte from an
+/// xorshift generator, so a seed picks the sequence of apple positions.
+pub struct SnakeInput {
+    random: u64,
+}
+
+impl SnakeInput {
+    pub fn new(seed: u64) -> Self {
+        let mut input = SnakeInput { random: 0 };
+        input.set_seed(seed);
+        input
+    }
+
+    /// Starts the sequence of random bytes over from `seed`.
+    pub fn set_seed(&mut self, seed: u64) {
+        // xorshift gets stuck at 0
+        self.random = seed.max(1);
+    }
+
+    /// Where the sequence of random bytes is; `set_seed` with it continues from there.
+    pub fn random_state(&self) -> u64 {
+        self.random
+    }
+}
+
+impl InputDevice for SnakeInput {
+    fn start_frame(&mut self, cpu: &mut CPU, buttons: JoypadButton) {
+        write_input(cpu, buttons);
+    }
+
+    fn before_step(&mut self, cpu: &mut CPU) {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        cpu.mem_write(0xfe, (self.random % 15) as u8 + 1);
+    }
+}
+
+/// Stores the direction held on the controller where the program reads its key.
+/// Nothing is written when no direction is held, so the snake keeps going.
+pub fn write_input(cpu: &mut CPU, buttons: JoypadButton) {
+    if buttons.contains(JoypadButton::UP) {
+        cpu.mem_write(0xff, 0x77);
+    } else if buttons.contains(JoypadButton::DOWN) {
+        cpu.mem_write(0xff, 0x73);
+    } else if buttons.contains(JoypadButton::LEFT) {
+        cpu.mem_write(0xff, 0x61);
+    } else if buttons.contains(JoypadButton::RIGHT) {
+        cpu.mem_write(0xff, 0x64);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+
+/// Test ROMs by blargg report through PRG-RAM instead of the screen:
+/// https://github.com/christopherpow/nes-test-roms/blob/master/README.txt
+///
+///  $6000       status: $80 running, $81 needs reset, $00-$7F result (0 = passed)
+///  $6001-$6003 signature $DE $B0 $61, written once the status is valid
+///  $6004-      zero terminated text output
+const STATUS: u16 = 0x6000;
+const SIGNATURE: u16 = 0x6001;
+const TEXT: u16 = 0x6004;
+const SIGNATURE_BYTES: [u8; 3] = [0xde, 0xb0, 0x61];
+
+/// Frames to wait before pressing reset; the ROMs ask for at least 100ms.
+const RESET_DELAY: u64 = 7;
+
+#[derive(Debug, PartialEq)]
+pub enum TestStatus {
+    Running,
+    NeedsReset,
+    Done(u8),
+}
+
+pub fn status(cpu: &CPU) -> Option<TestStatus> {
+    let signature = [
+        cpu.mem_peek(SIGNATURE),
+        cpu.mem_peek(SIGNATURE + 1),
+        cpu.mem_peek(SIGNATURE + 2),
+    ];
+    if signature != SIGNATURE_BYTES {
+        return None;
+    }
+
+    match cpu.mem_peek(STATUS) {
+        0x80 => Some(TestStatus::Running),
+        0x81 => Some(TestStatus::NeedsReset),
+        code => Some(TestStatus::Done(code)),
+    }
+}
+
+pub fn text(cpu: &CPU) -> String {
+    let mut out = String::new();
+    let mut addr = TEXT;
+    while addr < 0x8000 {
+        let byte = cpu.mem_peek(addr);
+        if byte == 0 {
+            break;
+        }
+        out.push(byte as char);
+        addr += 1;
+    }
+    out
+}
+
+/// Watches a test ROM frame by frame, pressing reset when asked.
+#[derive(Default)]
+pub struct TestRomMonitor {
+    frame: u64,
+    reset_at: Option<u64>,
+}
+
+impl TestRomMonitor {
+    pub fn new() -> Self {
+        TestRomMonitor::default()
+    }
+
+    /// Call once per frame. Returns the result code once the test is over.
+    pub fn on_frame(&mut self, cpu: &mut CPU) -> Option<u8> {
+        self.frame += 1;
+        match status(cpu) {
+            Some(TestStatus::Done(code)) => return Some(code),
+            Some(TestStatus::NeedsReset) if self.reset_at.is_none() => {
+                self.reset_at = Some(self.frame + RESET_DELAY);
+            }
+            _ => {}
+        }
+
+        if self.reset_at.is_some_and(|frame| self.frame >= frame) {
+            self.reset_at = None;
+            // the ROM writes $80 again once it restarted
+            cpu.mem_write(STATUS, 0x80);
+            cpu.reset();
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sign(cpu: &mut CPU, status: u8) {
+        cpu.mem_write(STATUS, status);
+        for (i, byte) in SIGNATURE_BYTES.iter().enumerate() {
+            cpu.mem_write(SIGNATURE + i as u16, *byte);
+        }
+    }
+
+    #[test]
+    fn test_status_needs_signature() {
+        let mut cpu = CPU::new();
+        assert_eq!(status(&cpu), None);
+
+        sign(&mut cpu, 0x80);
+        assert_eq!(status(&cpu), Some(TestStatus::Running));
+    }
+
+    #[test]
+    fn test_report_result_and_text() {
+        let mut cpu = CPU::new();
+        let mut monitor = TestRomMonitor::new();
+        sign(&mut cpu, 0x80);
+        assert_eq!(monitor.on_frame(&mut cpu), None);
+
+        for (i, byte) in b"\nPassed\n\0".iter().enumerate() {
+            cpu.mem_write(TEXT + i as u16, *byte);
+        }
+        cpu.mem_write(STATUS, 0);
+        assert_eq!(monitor.on_frame(&mut cpu), Some(0));
+        assert_eq!(text(&cpu), "\nPassed\n");
+    }
+
+    #[test]
+    fn test_reset_after_delay() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(0xfffc, 0x8000);
+        cpu.program_counter = 0x1234;
+        let mut monitor = TestRomMonitor::new();
+        sign(&mut cpu, 0x81);
+
+        for _ in 0..RESET_DELAY {
+            assert_eq!(monitor.on_frame(&mut cpu), None);
+            assert_eq!(cpu.program_counter, 0x1234);
+        }
+        monitor.on_frame(&mut cpu);
+        assert_eq!(cpu.program_counter, 0x8000);
+        assert_eq!(status(&cpu), Some(TestStatus::Running));
+    }
+}
@@ -0,0 +1,211 @@
+use crate::cpu::AddressingMode;
+use crate::cpu::CpuVariant;
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::opcode;
+
+const DOTS_PER_SCANLINE: usize = 341;
+const SCANLINES_PER_FRAME: usize = 262;
+
+/// Formats the instruction at the program counter like Nintendulator / nestest.log:
+///
+/// ```text
+/// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+/// ```
+///
+/// Must be called before the instruction executes. There is no PPU yet, so the
+/// PPU position is derived from the cycle count (3 dots per CPU cycle, rendering off).
+pub fn trace(cpu: &CPU) -> String {
+    let code = cpu.mem_peek(cpu.program_counter);
+    let ops = cpu.opcodes();
+
+    let begin = cpu.program_counter;
+    let mut hex_dump = vec![code];
+
+    let (mnemonic, asm) = match ops.get(&code) {
+        Some(ops) => {
+            for i in 1..ops.len as u16 {
+                hex_dump.push(cpu.mem_peek(begin.wrapping_add(i)));
+            }
+            (ops.mnemonic, operand(cpu, ops))
+        }
+        None => ("???", String::new()),
+    };
+
+    let hex_str = hex_dump
+        .iter()
+        .map(|z| format!("{:02x}", z))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, mnemonic, asm)
+        .trim_end()
+        .to_string();
+
+    let dots = cpu.cycles * 3;
+    format!(
+        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
+        asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.status.bits(), cpu.stack_pointer,
+    )
+    .to_ascii_uppercase()
+        + &format!(
+            " PPU:{:>3},{:>3} CYC:{}",
+            dots / DOTS_PER_SCANLINE % SCANLINES_PER_FRAME,
+            dots % DOTS_PER_SCANLINE,
+            cpu.cycles
+        )
+}
+
+fn operand(cpu: &CPU, ops: &opcode::OpCode) -> String {
+    let begin = cpu.program_counter;
+    let arg = |i: u16| cpu.mem_peek(begin.wrapping_add(i));
+    let arg_u16 = || (arg(2) as u16) << 8 | arg(1) as u16;
+
+    match (ops.len, &ops.mode) {
+        (1, _) => match ops.code {
+            0x0a | 0x4a | 0x2a | 0x6a | 0x1a | 0x3a => "A ".to_string(),
+            _ => String::new(),
+        },
+
+        (2, AddressingMode::Immediate) => format!("#${:02x}", arg(1)),
+        (2, AddressingMode::ZeroPage) => {
+            format!("${:02x} = {:02x}", arg(1), cpu.mem_peek(arg(1) as u16))
+        }
+        (2, AddressingMode::ZeroPage_X) => {
+            let addr = arg(1).wrapping_add(cpu.register_x) as u16;
+            format!("${:02x},X @ {:02x} = {:02x}", arg(1), addr, cpu.mem_peek(addr))
+        }
+        (2, AddressingMode::ZeroPage_Y) => {
+            let addr = arg(1).wrapping_add(cpu.register_y) as u16;
+            format!("${:02x},Y @ {:02x} = {:02x}", arg(1), addr, cpu.mem_peek(addr))
+        }
+        (2, AddressingMode::Indirect_X) => {
+            let ptr = arg(1).wrapping_add(cpu.register_x);
+            let addr = read_zero_page_u16(cpu, ptr);
+            format!(
+                "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
+                arg(1),
+                ptr,
+                addr,
+                cpu.mem_peek(addr)
+            )
+        }
+        (2, AddressingMode::Indirect_Y) => {
+            let base = read_zero_page_u16(cpu, arg(1));
+            let addr = base.wrapping_add(cpu.register_y as u16);
+            format!(
+                "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
+                arg(1),
+                base,
+                addr,
+                cpu.mem_peek(addr)
+            )
+        }
+        (2, AddressingMode::ZeroPage_Indirect) => {
+            let addr = read_zero_page_u16(cpu, arg(1));
+            format!("(${:02x}) = {:04x} = {:02x}", arg(1), addr, cpu.mem_peek(addr))
+        }
+        (2, _) => {
+            // relative branches
+            let target = begin.wrapping_add(2).wrapping_add(arg(1) as i8 as u16);
+            format!("${:04x}", target)
+        }
+
+        (3, AddressingMode::Absolute) => {
+            format!("${:04x} = {:02x}", arg_u16(), cpu.mem_peek(arg_u16()))
+        }
+        (3, AddressingMode::Absolute_X) => {
+            let addr = arg_u16().wrapping_add(cpu.register_x as u16);
+            format!("${:04x},X @ {:04x} = {:02x}", arg_u16(), addr, cpu.mem_peek(addr))
+        }
+        (3, AddressingMode::Absolute_Y) => {
+            let addr = arg_u16().wrapping_add(cpu.register_y as u16);
+            format!("${:04x},Y @ {:04x} = {:02x}", arg_u16(), addr, cpu.mem_peek(addr))
+        }
+        (3, _) => {
+            if ops.code == 0x6c {
+                // JMP indirect, with the page wrap bug the 65C02 fixed
+                let ptr = arg_u16();
+                let lo = cpu.mem_peek(ptr);
+                let wraps = ptr & 0x00ff == 0x00ff && cpu.variant() != CpuVariant::Cmos65C02;
+                let hi = cpu.mem_peek(if wraps { ptr & 0xff00 } else { ptr.wrapping_add(1) });
+                format!("(${:04x}) = {:04x}", ptr, (hi as u16) << 8 | lo as u16)
+            } else if ops.code == 0x7c {
+                // the 65C02's JMP (abs,X)
+                let ptr = arg_u16().wrapping_add(cpu.register_x as u16);
+                format!("(${:04x},X) @ {:04x} = {:04x}", arg_u16(), ptr, cpu.mem_peek_u16(ptr))
+            } else {
+                format!("${:04x}", arg_u16())
+            }
+        }
+
+        _ => String::new(),
+    }
+}
+
+fn read_zero_page_u16(cpu: &CPU, ptr: u8) -> u16 {
+    let lo = cpu.mem_peek(ptr as u16);
+    let hi = cpu.mem_peek(ptr.wrapping_add(1) as u16);
+    (hi as u16) << 8 | lo as u16
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_trace() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(100, 0xa2);
+        cpu.mem_write(101, 0x01);
+        cpu.mem_write(102, 0xca);
+        cpu.mem_write(103, 0x88);
+        cpu.mem_write(104, 0x00);
+        cpu.program_counter = 0x64;
+        cpu.register_a = 1;
+        cpu.register_x = 2;
+        cpu.register_y = 3;
+        cpu.cycles = 7;
+
+        let mut result: Vec<String> = vec![];
+        loop {
+            result.push(trace(&cpu));
+            if !cpu.step().unwrap() {
+                break;
+            }
+        }
+        assert_eq!(
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD PPU:  0, 21 CYC:7",
+            result[0]
+        );
+        assert_eq!(
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD PPU:  0, 27 CYC:9",
+            result[1]
+        );
+        assert_eq!(
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD PPU:  0, 33 CYC:11",
+            result[2]
+        );
+    }
+
+    #[test]
+    fn test_format_mem_access() {
+        let mut cpu = CPU::new();
+        // ORA ($33), Y
+        cpu.mem_write(100, 0x11);
+        cpu.mem_write(101, 0x33);
+
+        //data
+        cpu.mem_write(0x33, 0x00);
+        cpu.mem_write(0x34, 0x04);
+
+        //target cell
+        cpu.mem_write(0x400, 0xAA);
+
+        cpu.program_counter = 0x64;
+        cpu.register_y = 0;
+        assert_eq!(
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+            trace(&cpu)
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::joypad::JoypadButton;
+use crate::nes::Nes;
+use wasm_bindgen::prelude::*;
+
+/// The machine for JavaScript, built with `--features wasm` for `wasm32-unknown-unknown`.
+///
+/// The page drives it like any frontend drives `Nes`: it sets the buttons and runs a
+/// frame per animation frame, then draws the returned pixels.
+#[wasm_bindgen]
+pub struct WasmCpu {
+    nes: Nes,
+}
+
+#[wasm_bindgen]
+impl WasmCpu {
+    /// Loads `program` at $0600 and resets.
+    #[wasm_bindgen(constructor)]
+    pub fn new(program: &[u8]) -> Result<WasmCpu, JsError> {
+        let mut cpu = CPU::new();
+        cpu.load(program.to_vec()).map_err(|e| JsError::new(&e.to_string()))?;
+        cpu.reset();
+        Ok(WasmCpu { nes: Nes::new(cpu) })
+    }
+
+    /// The built-in snake program, see `snake::GAME_CODE` for its memory map.
+    pub fn snake(seed: u32) -> WasmCpu {
+        WasmCpu { nes: Nes::snake(seed.into()) }
+    }
+
+    /// Buttons in FM2 order, `RLDUTSBA` from bit 7 to bit 0.
+    pub fn set_input(&mut self, buttons: u8) {
+        self.nes.set_input(JoypadButton::from_bits_truncate(buttons));
+    }
+
+    /// Runs a frame and returns its RGB24 pixels.
+    pub fn run_frame(&mut self) -> Result<Vec<u8>, JsError> {
+        let frame = self.nes.run_frame().map_err(|e| JsError::new(&e.to_string()))?;
+        Ok(frame.pixels.to_vec())
+    }
+
+    /// Whether the last frame ended because the CPU halted.
+    pub fn halted(&self) -> bool {
+        self.nes.cpu().halt_reason().is_some()
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.nes.cpu().mem_peek(addr)
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        self.nes.cpu_mut().mem_write(addr, data);
+    }
+
+    /// Copies `len` bytes from `start`.
+    pub fn memory(&self, start: u16, len: u16) -> Vec<u8> {
+        (0..len).map(|i| self.nes.cpu().mem_peek(start.wrapping_add(i))).collect()
+    }
+
+    pub fn program_counter(&self) -> u16 {
+        self.nes.cpu().program_counter
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_snake() {
+        let mut snake = WasmCpu::snake(5);
+        snake.set_input(JoypadButton::UP.bits());
+        let pixels = snake.run_frame().unwrap();
+        assert_eq!(pixels.len(), 32 * 32 * 3);
+        assert!(!snake.halted());
+        assert_eq!(snake.read(0xff), b'w');
+    }
+}
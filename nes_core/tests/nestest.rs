@@ -0,0 +1,44 @@
+//! Runs nestest in automation mode and compares the trace with its golden log.
+//! https://www.qmtpro.com/~nes/misc/nestest.txt
+//!
+//! The ROM and log are not in the repository, so the test is ignored by default; run
+//! `tests/roms/fetch.sh`, then `cargo test -p nes_core --test nestest -- --ignored`.
+
+use nes_core::cartridge::Rom;
+use nes_core::cpu::CPU;
+use nes_core::trace::trace;
+use std::fs;
+use std::path::Path;
+
+/// The last line testing official opcodes; the unofficial ones, which are not
+/// implemented, start right after it.
+const OFFICIAL_LINES: usize = 5003;
+
+#[test]
+#[ignore = "needs tests/roms/fetch.sh"]
+fn test_nestest_golden_log() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms");
+    let rom = fs::read(dir.join("nestest.nes")).expect("nestest.nes is missing, run tests/roms/fetch.sh");
+    let rom = Rom::new(&rom).unwrap();
+    let golden = fs::read_to_string(dir.join("nestest.log")).expect("nestest.log is missing, run tests/roms/fetch.sh");
+    let golden: Vec<&str> = golden.lines().map(str::trim_end).collect();
+    assert!(
+        golden[OFFICIAL_LINES].contains(" *"),
+        "line {} of nestest.log is not the first unofficial opcode",
+        OFFICIAL_LINES + 1
+    );
+
+    let mut cpu = CPU::new();
+    cpu.load_rom(&rom).unwrap();
+    cpu.reset();
+    cpu.program_counter = 0xc000;
+
+    for (line_no, expected) in golden[..OFFICIAL_LINES].iter().enumerate() {
+        assert_eq!(trace(&cpu), *expected, "first divergence from nestest.log at line {}", line_no + 1);
+        if !cpu.step().unwrap() {
+            panic!("BRK stopped the program at line {}", line_no + 1);
+        }
+    }
+    // stopped right at the first unofficial opcode
+    assert!(golden[OFFICIAL_LINES].starts_with(&format!("{:04X}", cpu.program_counter)));
+}
@@ -0,0 +1,7 @@
+#!/bin/sh
+# Downloads the fixtures of tests/nestest.rs next to this script.
+set -e
+cd "$(dirname "$0")"
+for file in nestest.nes nestest.log; do
+    curl -fsSL -o "$file" "https://www.qmtpro.com/~nes/misc/$file"
+done
@@ -0,0 +1,288 @@
+use clap::Parser;
+use clap::Subcommand;
+use clap::ValueEnum;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use nes_core::cpu::CpuVariant;
+use nes_core::disasm::parse_address;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    /// Video frames per second https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+    #[cfg(feature = "sdl-frontend")]
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 60.0988,
+            Region::Pal => 50.0070,
+            Region::Dendy => 50.0070,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum CpuModel {
+    /// Ricoh 2A03 of the NES, without decimal mode
+    #[value(name = "2a03")]
+    Ricoh2A03,
+    /// NMOS 6502
+    #[value(name = "6502")]
+    Nmos6502,
+    /// CMOS 65C02
+    #[value(name = "65c02")]
+    Cmos65C02,
+}
+
+impl CpuModel {
+    pub fn variant(&self) -> CpuVariant {
+        match self {
+            CpuModel::Ricoh2A03 => CpuVariant::Ricoh2A03,
+            CpuModel::Nmos6502 => CpuVariant::Nmos6502,
+            CpuModel::Cmos65C02 => CpuVariant::Cmos65C02,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn filter(&self) -> LevelFilter {
+        match self {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// NES emulator. Runs the built-in snake program when no ROM is given.
+#[derive(Parser, Debug)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub struct Options {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// iNES (.nes) ROM to run
+    pub rom: Option<PathBuf>,
+
+    /// Window scale factor
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..=64))]
+    pub scale: u32,
+
+    /// Start in fullscreen
+    #[arg(long)]
+    pub fullscreen: bool,
+
+    /// Console timing to emulate
+    #[arg(long, value_enum, default_value_t = Region::Ntsc)]
+    pub region: Region,
+
+    /// Processor to emulate, or whose instruction set `disasm` decodes
+    #[arg(long, value_enum, default_value_t = CpuModel::Ricoh2A03, global = true)]
+    pub cpu: CpuModel,
+
+    /// Do not open an audio device (there is no APU yet, so audio is always silent)
+    #[arg(long)]
+    pub no_audio: bool,
+
+    /// Start with audio muted
+    #[arg(long)]
+    pub mute: bool,
+
+    /// Run without opening a window
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Quit after this many frames
+    #[arg(long, value_name = "N")]
+    pub frames: Option<u64>,
+
+    /// Follow the blargg test ROM protocol at $6000: print the test output and
+    /// exit with its result code (0 when passed, 128 on timeout). Implies --detect-loops
+    #[arg(long, requires = "headless")]
+    pub test_rom: bool,
+
+    /// Stop once the program jumps or branches to itself, which it can never leave
+    #[arg(long)]
+    pub detect_loops: bool,
+
+    /// Start paused in the debugger, reading commands from stdin (type help)
+    #[arg(long)]
+    pub debug: bool,
+
+    /// Wait for a gdb remote connection on this local TCP port and start stopped
+    #[arg(long, value_name = "PORT", conflicts_with = "debug")]
+    pub gdb: Option<u16>,
+
+    /// Log which PRG-ROM bytes run as code or are read as data to an FCEUX .cdl file,
+    /// adding to the file if it exists
+    #[arg(long, value_name = "FILE")]
+    pub cdl: Option<PathBuf>,
+
+    /// Write a PRG-ROM coverage summary when quitting, HTML if FILE ends in .html
+    #[arg(long, value_name = "FILE")]
+    pub coverage: Option<PathBuf>,
+
+    /// Save state to load before the first frame
+    #[arg(long, value_name = "FILE")]
+    pub state: Option<PathBuf>,
+
+    /// FM2 movie to play back
+    #[arg(long, value_name = "FILE")]
+    pub movie: Option<PathBuf>,
+
+    /// Memory for the snapshots that backspace rewinds through
+    #[arg(long, value_name = "MB", default_value_t = 32, value_parser = clap::value_parser!(u64).range(1..=4096))]
+    pub rewind_budget: u64,
+
+    /// Frames between two rewind snapshots
+    #[arg(long, value_name = "N", default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rewind_interval: u32,
+
+    /// Seed for the random source so sessions reproduce exactly
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+
+    /// Verbosity of the messages printed to stderr
+    #[arg(long, value_enum, default_value_t = LogLevel::Info)]
+    pub log_level: LogLevel,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum Command {
+    /// Print the disassembly of a ROM's PRG-ROM, or of the built-in snake program
+    Disasm {
+        /// iNES (.nes) ROM to disassemble
+        rom: Option<PathBuf>,
+
+        /// First address to disassemble, in hex
+        #[arg(long, value_parser = parse_address)]
+        start: Option<u16>,
+
+        /// Last address to disassemble, in hex
+        #[arg(long, value_parser = parse_address)]
+        end: Option<u16>,
+    },
+}
+
+/// Writes log records to stderr, prefixed by their level.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Info => eprintln!("{}", record.args()),
+            level => eprintln!("{}: {}", level.as_str().to_lowercase(), record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+pub fn init_logger(level: LogLevel) {
+    log::set_logger(&LOGGER).expect("logger is initialized once");
+    log::set_max_level(level.filter());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let options = Options::try_parse_from(["nes_emulator"]).unwrap();
+
+        assert_eq!(options.rom, None);
+        assert_eq!(options.command, None);
+        assert_eq!(options.scale, 10);
+        assert_eq!(options.region, Region::Ntsc);
+        assert_eq!(options.log_level, LogLevel::Info);
+        assert_eq!((options.rewind_budget, options.rewind_interval), (32, 2));
+        assert!(!options.headless);
+    }
+
+    #[test]
+    fn test_parse_all_options() {
+        let options = Options::try_parse_from([
+            "nes_emulator", "game.nes", "--scale", "3", "--fullscreen", "--region", "pal", "--cpu", "65c02",
+            "--no-audio", "--mute", "--headless", "--frames", "600", "--state", "game.ss1",
+            "--movie", "run.fm2", "--seed", "42", "--log-level", "debug", "--test-rom", "--debug",
+            "--detect-loops", "--cdl", "game.cdl", "--coverage", "coverage.html", "--rewind-budget", "8",
+            "--rewind-interval", "5",
+        ])
+        .unwrap();
+
+        assert_eq!(options.rom, Some(PathBuf::from("game.nes")));
+        assert_eq!(options.scale, 3);
+        assert!(options.fullscreen && options.no_audio && options.mute && options.headless);
+        assert!(options.test_rom && options.debug && options.detect_loops);
+        assert_eq!(options.region, Region::Pal);
+        assert_eq!(options.cpu.variant(), CpuVariant::Cmos65C02);
+        assert_eq!(options.frames, Some(600));
+        assert_eq!(options.state, Some(PathBuf::from("game.ss1")));
+        assert_eq!(options.movie, Some(PathBuf::from("run.fm2")));
+        assert_eq!(options.cdl, Some(PathBuf::from("game.cdl")));
+        assert_eq!(options.coverage, Some(PathBuf::from("coverage.html")));
+        assert_eq!(options.seed, 42);
+        assert_eq!(options.log_level, LogLevel::Debug);
+        assert_eq!((options.rewind_budget, options.rewind_interval), (8, 5));
+        assert!(Options::try_parse_from(["nes_emulator", "--rewind-interval", "0"]).is_err());
+        assert!(Options::try_parse_from(["nes_emulator", "--rewind-budget", "0"]).is_err());
+        assert!(Options::try_parse_from(["nes_emulator", "--rewind-budget", "18446744073709551615"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_disasm() {
+        let options =
+            Options::try_parse_from(["nes_emulator", "disasm", "game.nes", "--start", "$c000", "--end", "0xC0FF"])
+                .unwrap();
+        assert_eq!(
+            options.command,
+            Some(Command::Disasm {
+                rom: Some(PathBuf::from("game.nes")),
+                start: Some(0xc000),
+                end: Some(0xc0ff),
+            })
+        );
+
+        let options = Options::try_parse_from(["nes_emulator", "disasm", "--cpu", "65c02"]).unwrap();
+        assert_eq!(options.cpu.variant(), CpuVariant::Cmos65C02);
+
+        assert!(Options::try_parse_from(["nes_emulator", "disasm", "--start", "10000"]).is_err());
+        assert!(Options::try_parse_from(["nes_emulator", "disasm", "--headless"]).is_err());
+    }
+
+    #[test]
+    fn test_reject_invalid_values() {
+        assert!(Options::try_parse_from(["nes_emulator", "--scale", "0"]).is_err());
+        assert!(Options::try_parse_from(["nes_emulator", "--region", "secam"]).is_err());
+        assert!(Options::try_parse_from(["nes_emulator", "--frames", "-1"]).is_err());
+        assert!(Options::try_parse_from(["nes_emulator", "--test-rom"]).is_err());
+        assert!(Options::try_parse_from(["nes_emulator", "--debug", "--gdb", "1234"]).is_err());
+    }
+}
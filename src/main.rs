@@ -1,140 +1,731 @@
-pub mod cpu;
-pub mod opcode;
-use cpu::Mem;
-use cpu::CPU;
-use rand::Rng;
-use sdl2::event::Event;
-use sdl2::EventPump;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use sdl2::pixels::PixelFormatEnum;
+mod cli;
+#[cfg(feature = "sdl-frontend")]
+mod sdl;
 
-#[macro_use]
-extern crate lazy_static;
+use clap::Parser;
+use cli::Command;
+use cli::Options;
+use nes_core::cartridge::BatteryRam;
+use nes_core::cartridge::Rom;
+use nes_core::cdl::CodeDataLog;
+use nes_core::cpu::CPU;
+use nes_core::cpu::CpuVariant;
+use nes_core::cpu::HaltReason;
+#[cfg(feature = "debugger")]
+use nes_core::debugger;
+#[cfg(feature = "debugger")]
+use nes_core::debugger::Debugger;
+#[cfg(feature = "debugger")]
+use nes_core::debugger::Monitor;
+use nes_core::disasm;
+use nes_core::error::EmuError;
+#[cfg(feature = "debugger")]
+use nes_core::gdbstub::GdbStub;
+use nes_core::joypad::JoypadButton;
+use nes_core::movie::Movie;
+use nes_core::movie::MovieCommand;
+use nes_core::movie::MovieStart;
+use nes_core::nes::Nes;
+use nes_core::savestate;
+use nes_core::savestate::SaveState;
+use nes_core::savestate::Thumbnail;
+use nes_core::snake;
+use nes_core::snake::SnakeInput;
+use nes_core::testrom;
+use nes_core::testrom::TestRomMonitor;
+use nes_core::testrom::TestStatus;
+use std::cell::RefCell;
+#[cfg(feature = "sdl-frontend")]
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
 
 #[macro_use]
-extern crate bitflags;
-
-fn color(byte: u8) -> Color {
-    match byte {
-        0 => sdl2::pixels::Color::BLACK,
-        1 => sdl2::pixels::Color::WHITE,
-        2 | 9 => sdl2::pixels::Color::GREY,
-        3 | 10 => sdl2::pixels::Color::RED,
-        4 | 11 => sdl2::pixels::Color::GREEN,
-        5 | 12 => sdl2::pixels::Color::BLUE,
-        6 | 13 => sdl2::pixels::Color::MAGENTA,
-        7 | 14 => sdl2::pixels::Color::YELLOW,
-        _ => sdl2::pixels::Color::CYAN,
-    }
-}
-
-fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
-    let mut frame_idx = 0;
-    let mut update = false;
-    for i in 0x0200..0x600 {
-        let color_idx = cpu.mem_read(i as u16);
-        let (b1, b2, b3) = color(color_idx).rgb();
-        if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
-            frame[frame_idx] = b1;
-            frame[frame_idx + 1] = b2;
-            frame[frame_idx + 2] = b3;
-            update = true;
-        }
-        frame_idx += 3;
-    }
-    update
-}
-
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
-    for event in event_pump.poll_iter() {
-        match event {
-            Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                std::process::exit(0)
-            },
-            Event::KeyDown { keycode: Some(Keycode::W), .. } => {
-                cpu.mem_write(0xff, 0x77);
-            },
-            Event::KeyDown { keycode: Some(Keycode::S), .. } => {
-                cpu.mem_write(0xff, 0x73);
-            },
-            Event::KeyDown { keycode: Some(Keycode::A), .. } => {
-                cpu.mem_write(0xff, 0x61);
+extern crate log;
+
+/// Exit code of `--test-rom` runs that hit the frame limit or halt without a result.
+const TEST_ROM_TIMEOUT: i32 = 128;
+
+/// Exit code when the CPU hits an error, such as an unknown opcode.
+const EMULATION_ERROR: i32 = 3;
+
+/// How many frames run between two flushes of battery-backed PRG-RAM, a minute at 60 fps.
+const BATTERY_FLUSH_INTERVAL: u64 = 3600;
+
+/// Numbered save state slots stored next to the program as `<name>.ss<slot>`.
+struct StateSlots {
+    #[cfg_attr(not(feature = "sdl-frontend"), allow(dead_code))]
+    base: PathBuf,
+    rom_hash: u32,
+    #[cfg_attr(not(feature = "sdl-frontend"), allow(dead_code))]
+    slot: u8,
+}
+
+#[cfg(feature = "sdl-frontend")]
+impl StateSlots {
+    fn path(&self) -> PathBuf {
+        self.base.with_extension(format!("ss{}", self.slot))
+    }
+
+    /// Returns `false` when the state could not be written.
+    fn save(&self, cpu: &CPU, screen_state: &[u8]) -> bool {
+        let thumbnail = Thumbnail { width: 32, height: 32, pixels: screen_state.to_vec() };
+        let path = self.path();
+        match SaveState::capture(cpu, self.rom_hash, thumbnail).save_to_file(&path) {
+            Ok(()) => info!("saved state to {}", path.display()),
+            Err(e) => {
+                error!("could not write {}: {}", path.display(), e);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns `false` when no state was loaded.
+    fn load(&self, cpu: &mut CPU) -> bool {
+        if let Err(e) = SaveState::load_from_file(&self.path(), self.rom_hash).and_then(|state| state.restore(cpu)) {
+            error!("could not load state: {}", e);
+            return false;
+        }
+        true
+    }
+}
+
+/// Keyboard state sampled once per frame; taps shorter than a frame still count.
+#[derive(Default)]
+struct KeyboardPad {
+    held: JoypadButton,
+    tapped: JoypadButton,
+}
+
+impl KeyboardPad {
+    fn sample(&mut self) -> JoypadButton {
+        let buttons = self.held | self.tapped;
+        self.tapped = JoypadButton::empty();
+        buttons
+    }
+}
+
+/// F9 starts and stops recording `<name>.fm2`, F10 plays it back. Both happen between
+/// frames, so the movie's first frame is a whole frame after its start state.
+enum MovieMode {
+    Off,
+    #[cfg(feature = "sdl-frontend")]
+    Recording(Movie),
+    Playing(Movie, usize),
+}
+
+impl MovieMode {
+    fn is_off(&self) -> bool {
+        matches!(self, MovieMode::Off)
+    }
+
+    /// Input for the next frame: recorded from the keyboard or read from the movie.
+    fn next_input(&mut self, live: JoypadButton) -> JoypadButton {
+        let played = match self {
+            MovieMode::Off => return live,
+            #[cfg(feature = "sdl-frontend")]
+            MovieMode::Recording(movie) => {
+                movie.frames.push(live);
+                return live;
+            }
+            MovieMode::Playing(movie, frame) => {
+                *frame += 1;
+                movie.input(*frame - 1)
+            }
+        };
+        played.unwrap_or_else(|| {
+            info!("movie finished");
+            *self = MovieMode::Off;
+            live
+        })
+    }
+}
+
+/// Where the movie was when a slot was saved during the recording.
+#[cfg(feature = "sdl-frontend")]
+struct RerecordPoint {
+    frames: usize,
+    random: Option<u64>,
+}
+
+/// Code/Data Logger output, written when the emulator quits.
+struct CdlOutput {
+    log: Rc<RefCell<CodeDataLog>>,
+    path: Option<PathBuf>,
+    /// HTML when the name ends in .html, text otherwise
+    report: Option<PathBuf>,
+    title: String,
+}
+
+impl CdlOutput {
+    fn new(rom: &Rom, rom_path: &Path, path: Option<PathBuf>, report: Option<PathBuf>) -> Result<Self, String> {
+        let mut log = CodeDataLog::new(rom);
+        if let Some(path) = path.as_deref().filter(|path| path.exists()) {
+            let saved = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+            log.merge_cdl(&saved).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        let title = rom_path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        Ok(CdlOutput { log: Rc::new(RefCell::new(log)), path, report, title })
+    }
+
+    fn write(&self) {
+        let log = self.log.borrow();
+        if let Some(path) = &self.path {
+            match log.save_to_file(path) {
+                Ok(()) => info!("saved code/data log to {}", path.display()),
+                Err(e) => error!("could not write {}: {}", path.display(), e),
+            }
+        }
+        if let Some(path) = &self.report {
+            let report = if path.extension().is_some_and(|ext| ext == "html") {
+                log.html_report(&self.title)
+            } else {
+                log.text_report()
+            };
+            if let Err(e) = std::fs::write(path, report) {
+                error!("could not write {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Frontend state that is not part of the emulated machine.
+struct Session {
+    slots: StateSlots,
+    battery: Option<BatteryRam>,
+    pad: KeyboardPad,
+    movie: MovieMode,
+    /// slots saved during the current recording, which loading rerecords from
+    #[cfg(feature = "sdl-frontend")]
+    rerecord_points: HashMap<u8, RerecordPoint>,
+    power_on: SaveState,
+    /// seeds the snake program's random bytes when recording starts, see `SnakeInput::set_seed`
+    #[cfg(feature = "sdl-frontend")]
+    seed: u64,
+    /// input of the built-in snake program; `None` when a cartridge runs
+    snake: Option<Rc<RefCell<SnakeInput>>>,
+    frame_limit: Option<u64>,
+    /// REPL debugger or gdb connection
+    #[cfg(feature = "debugger")]
+    debugger: Option<Box<dyn Monitor>>,
+    cdl: Option<CdlOutput>,
+}
+
+impl Session {
+    /// Starts the snake program's random bytes over from `seed`.
+    fn reseed(&self, seed: u64) {
+        if let Some(snake) = &self.snake {
+            snake.borrow_mut().set_seed(seed);
+        }
+    }
+
+    #[cfg(feature = "sdl-frontend")]
+    fn toggle_recording(&mut self, nes: &mut Nes) {
+        let path = self.slots.base.with_extension("fm2");
+        match std::mem::replace(&mut self.movie, MovieMode::Off) {
+            MovieMode::Recording(movie) => match movie.save_to_file(&path) {
+                Ok(()) => info!("saved movie to {}", path.display()),
+                Err(e) => error!("could not write {}: {}", path.display(), e),
             },
-            Event::KeyDown { keycode: Some(Keycode::D), .. } => {
-                cpu.mem_write(0xff, 0x64);
+            _ => {
+                let thumbnail = Thumbnail { width: 0, height: 0, pixels: vec![] };
+                let state = SaveState::capture(nes.cpu(), self.slots.rom_hash, thumbnail).to_bytes();
+                let name = self.slots.base.file_stem().unwrap_or_default().to_string_lossy();
+                let mut movie = Movie::new(&name, MovieStart::SaveState(state));
+                movie.seed = self.seed;
+                self.reseed(self.seed);
+                self.rerecord_points.clear();
+                self.movie = MovieMode::Recording(movie);
+                info!("recording movie");
+            }
+        }
+    }
+
+    fn start_playback(&mut self, nes: &mut Nes, path: &Path) -> Result<(), String> {
+        let movie = Movie::load_from_file(path)?;
+        match &movie.start {
+            MovieStart::PowerOn => self.power_on.restore(nes.cpu_mut())?,
+            MovieStart::SaveState(data) => {
+                let state = SaveState::from_bytes(data)?;
+                if state.rom_hash != self.slots.rom_hash {
+                    return Err(format!("{} was recorded with another ROM", path.display()));
+                }
+                state.restore(nes.cpu_mut())?;
+            }
+        }
+        self.reseed(movie.seed);
+        self.movie = MovieMode::Playing(movie, 0);
+        Ok(())
+    }
+
+    #[cfg(feature = "sdl-frontend")]
+    fn save_slot(&mut self, nes: &Nes, screen_state: &[u8]) {
+        if !self.slots.save(nes.cpu(), screen_state) {
+            return;
+        }
+        if let MovieMode::Recording(movie) = &self.movie {
+            let point = RerecordPoint { frames: movie.frames.len(), random: self.snake.as_ref().map(|snake| snake.borrow().random_state()) };
+            self.rerecord_points.insert(self.slots.slot, point);
+        }
+    }
+
+    /// Loads the current slot. While recording that is a rerecord: the movie goes back
+    /// to the frame the slot was saved at, so only slots saved during the recording load.
+    /// During playback nothing is loaded.
+    #[cfg(feature = "sdl-frontend")]
+    fn load_slot(&mut self, nes: &mut Nes) {
+        let movie = match &mut self.movie {
+            MovieMode::Off => {
+                self.slots.load(nes.cpu_mut());
+                return;
+            }
+            MovieMode::Recording(movie) => movie,
+            MovieMode::Playing(..) => return,
+        };
+        let (frames, random) = match self.rerecord_points.get(&self.slots.slot) {
+            Some(point) => (point.frames, point.random),
+            None => {
+                error!("slot {} was not saved during this recording", self.slots.slot);
+                return;
             }
-            _ => {/* do nothing */}
+        };
+        if !self.slots.load(nes.cpu_mut()) {
+            return;
         }
+        if let (Some(snake), Some(random)) = (&self.snake, random) {
+            snake.borrow_mut().set_seed(random);
+        }
+        movie.truncate(frames);
+        movie.rerecord_count += 1;
+        self.rerecord_points.retain(|_, point| point.frames <= frames);
+    }
+
+    fn flush_battery(&mut self, cpu: &CPU) {
+        if let Some(battery) = &mut self.battery {
+            if let Err(e) = battery.flush(cpu) {
+                error!("could not write {}: {}", battery.path().display(), e);
+            }
+        }
+    }
+
+    /// Writes everything that outlives the session.
+    fn shutdown(&mut self, cpu: &CPU) {
+        self.flush_battery(cpu);
+        if let Some(cdl) = &self.cdl {
+            cdl.write();
+        }
+    }
+
+    /// Ends the session once the program stopped on its own: when the CPU halted, or on
+    /// an emulation error, which is logged and makes the process exit with a failure.
+    fn stop(&mut self, cpu: &CPU, result: Result<HaltReason, EmuError>) {
+        match result {
+            Ok(reason @ HaltReason::Break { .. }) => debug!("stopped: {}", reason),
+            Ok(reason) => info!("stopped: {}", reason),
+            Err(e) => {
+                error!("{}", e);
+                self.quit(cpu, EMULATION_ERROR);
+            }
+        }
+        self.shutdown(cpu);
+    }
+
+    fn quit(&mut self, cpu: &CPU, code: i32) -> ! {
+        self.shutdown(cpu);
+        std::process::exit(code)
     }
+
+    /// Input for the next frame: from the keyboard, or from the movie being played, whose
+    /// resets for the frame are carried out first.
+    fn next_input(&mut self, nes: &mut Nes) -> JoypadButton {
+        if let MovieMode::Playing(movie, frame) = &self.movie {
+            match movie.command(*frame) {
+                Some(MovieCommand::SoftReset) => nes.cpu_mut().reset(),
+                Some(MovieCommand::HardReset) => {
+                    self.power_on.restore(nes.cpu_mut()).expect("the power-on state is of this CPU");
+                    self.reseed(movie.seed);
+                }
+                None => {}
+            }
+        }
+        self.movie.next_input(self.pad.sample())
+    }
+
+    /// Runs after every frame.
+    fn frame_done(&mut self, nes: &Nes) {
+        if nes.frame_count().is_multiple_of(BATTERY_FLUSH_INTERVAL) {
+            self.flush_battery(nes.cpu());
+        }
+    }
+
+    /// Lets the debugger stop before the next instruction, blocking on its input.
+    #[cfg(feature = "debugger")]
+    fn debug(&mut self, cpu: &mut CPU) {
+        if let Some(debugger) = &mut self.debugger {
+            if debugger.before_instruction(cpu) {
+                debugger.wait(cpu);
+            }
+            if debugger.quit_requested() {
+                self.quit(cpu, 0);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "debugger"))]
+    fn debug(&mut self, _cpu: &mut CPU) {}
+
+    fn frame_limit_reached(&self, nes: &Nes) -> bool {
+        self.frame_limit.is_some_and(|limit| nes.frame_count() >= limit)
+    }
+}
+
+struct Program {
+    cpu: CPU,
+    slots: StateSlots,
+    battery: Option<BatteryRam>,
+    /// `None` for the built-in snake program
+    rom: Option<Rom>,
+}
+
+fn load_program(rom_path: Option<&Path>, game_code: Vec<u8>, variant: CpuVariant) -> Result<Program, String> {
+    let mut cpu = CPU::with_variant(variant);
+    let rom_path = match rom_path {
+        Some(rom_path) => rom_path,
+        None => {
+            let rom_hash = savestate::rom_hash(&game_code);
+            cpu.load(game_code).map_err(|e| e.to_string())?;
+            cpu.reset();
+            let slots = StateSlots { base: PathBuf::from("snake"), rom_hash, slot: 0 };
+            return Ok(Program { cpu, slots, battery: None, rom: None });
+        }
+    };
+
+    let raw = std::fs::read(rom_path)
+        .map_err(|e| format!("cannot read ROM {}: {}", rom_path.display(), e))?;
+    let rom = Rom::new(&raw).map_err(|e| format!("{} is not a valid ROM: {}", rom_path.display(), e))?;
+    cpu.load_rom(&rom).map_err(|e| format!("cannot load {}: {}", rom_path.display(), e))?;
+
+    let mut battery = None;
+    if rom.battery {
+        let mut battery_ram = BatteryRam::new(rom_path);
+        battery_ram
+            .load(&mut cpu)
+            .map_err(|e| format!("cannot read {}: {}", battery_ram.path().display(), e))?;
+        battery = Some(battery_ram);
+    }
+    cpu.reset();
+
+    let slots = StateSlots { base: rom_path.to_path_buf(), rom_hash: savestate::rom_hash(&raw), slot: 0 };
+    Ok(Program { cpu, slots, battery, rom: Some(rom) })
+}
+
+/// Prints the disassembly of `start..=end`, by default the whole program.
+fn run_disasm(
+    rom_path: Option<&Path>,
+    game_code: Vec<u8>,
+    variant: CpuVariant,
+    start: Option<u16>,
+    end: Option<u16>,
+) -> Result<(), String> {
+    let mut cpu = CPU::with_variant(variant);
+    let (default_start, default_end) = match rom_path {
+        Some(rom_path) => {
+            let raw = std::fs::read(rom_path)
+                .map_err(|e| format!("cannot read ROM {}: {}", rom_path.display(), e))?;
+            let rom = Rom::new(&raw).map_err(|e| format!("{} is not a valid ROM: {}", rom_path.display(), e))?;
+            cpu.load_rom(&rom).map_err(|e| format!("cannot load {}: {}", rom_path.display(), e))?;
+            if start.is_none() && end.is_none() {
+                print!("{}", disasm::disassemble_rom(variant, &rom).map_err(|e| e.to_string())?);
+                return Ok(());
+            }
+            (if rom.prg_rom.len() <= 0x4000 { 0xc000 } else { 0x8000 }, 0xffff)
+        }
+        None => {
+            let len = game_code.len() as u16;
+            cpu.load(game_code).map_err(|e| e.to_string())?;
+            (0x0600, 0x0600 + len - 1)
+        }
+    };
+
+    let (start, end) = (start.unwrap_or(default_start), end.unwrap_or(default_end));
+    if start > end {
+        return Err(format!("start ${:04X} is after end ${:04X}", start, end));
+    }
+    print!("{}", disasm::disassemble(variant, &cpu, start, end));
+    Ok(())
+}
+
+fn run_headless(mut nes: Nes, mut session: Session, test_rom: bool) {
+    let playing = !session.movie.is_off();
+    if !playing && session.frame_limit.is_none() {
+        warn!("running headless without --frames or --movie, stop with ctrl-c");
+    }
+    let mut monitor = test_rom.then(TestRomMonitor::new);
+
+    let result = loop {
+        let buttons = session.next_input(&mut nes);
+        if playing && session.movie.is_off() {
+            session.quit(nes.cpu(), 0);
+        }
+        nes.set_input(buttons);
+        if let Err(e) = nes.run_frame_with_callback(|cpu| session.debug(cpu)) {
+            break Err(e);
+        }
+        if let Some(reason) = nes.cpu().halt_reason() {
+            break Ok(reason);
+        }
+
+        if let Some(code) = monitor.as_mut().and_then(|monitor| monitor.on_frame(nes.cpu_mut())) {
+            print!("{}", testrom::text(nes.cpu()));
+            info!("test finished with result {}", code);
+            session.quit(nes.cpu(), code as i32);
+        }
+
+        session.frame_done(&nes);
+        if session.frame_limit_reached(&nes) {
+            if monitor.is_some() {
+                print!("{}", testrom::text(nes.cpu()));
+                error!("test did not finish within {} frames", nes.frame_count());
+                session.quit(nes.cpu(), TEST_ROM_TIMEOUT);
+            }
+            session.quit(nes.cpu(), 0);
+        }
+    };
+
+    let cpu = nes.cpu();
+    if let (Some(_), Ok(reason)) = (&monitor, &result) {
+        print!("{}", testrom::text(cpu));
+        if let Some(TestStatus::Done(code)) = testrom::status(cpu) {
+            info!("test finished with result {}", code);
+            session.quit(cpu, code as i32);
+        }
+        error!("test stopped without a result: {}", reason);
+        session.quit(cpu, TEST_ROM_TIMEOUT);
+    }
+    session.stop(cpu, result);
+}
+
+/// Starts the debugger `--debug` or `--gdb` asked for.
+#[cfg(feature = "debugger")]
+fn attach_debugger(session: &mut Session, options: &Options) -> Result<(), String> {
+    if options.debug {
+        session.debugger = Some(Box::new(Debugger::new(debugger::stdin_lines())));
+    }
+    if let Some(port) = options.gdb {
+        let stub = GdbStub::listen(port).map_err(|e| format!("could not listen for gdb on port {}: {}", port, e))?;
+        session.debugger = Some(Box::new(stub));
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "debugger"))]
+fn attach_debugger(_session: &mut Session, options: &Options) -> Result<(), String> {
+    if options.debug || options.gdb.is_some() {
+        return Err("built without the debugger feature".to_string());
+    }
+    Ok(())
 }
 
 fn main() {
-    // init sdl2
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem
-        .window("Snake game", (32.0 * 10.0) as u32, (32.0 * 10.0) as u32)
-        .position_centered()
-        .build().unwrap();
-
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-    let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(10.0, 10.0).unwrap();
-
-    let creator = canvas.texture_creator();
-    let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 32, 32).unwrap();
-    
-
-    let game_code = vec![
-        0x20, 0x06, 0x06, 0x20, 0x38, 0x06, 0x20, 0x0d, 0x06, 0x20, 0x2a, 0x06, 0x60, 0xa9, 0x02,
-        0x85, 0x02, 0xa9, 0x04, 0x85, 0x03, 0xa9, 0x11, 0x85, 0x10, 0xa9, 0x10, 0x85, 0x12, 0xa9,
-        0x0f, 0x85, 0x14, 0xa9, 0x04, 0x85, 0x11, 0x85, 0x13, 0x85, 0x15, 0x60, 0xa5, 0xfe, 0x85,
-        0x00, 0xa5, 0xfe, 0x29, 0x03, 0x18, 0x69, 0x02, 0x85, 0x01, 0x60, 0x20, 0x4d, 0x06, 0x20,
-        0x8d, 0x06, 0x20, 0xc3, 0x06, 0x20, 0x19, 0x07, 0x20, 0x20, 0x07, 0x20, 0x2d, 0x07, 0x4c,
-        0x38, 0x06, 0xa5, 0xff, 0xc9, 0x77, 0xf0, 0x0d, 0xc9, 0x64, 0xf0, 0x14, 0xc9, 0x73, 0xf0,
-        0x1b, 0xc9, 0x61, 0xf0, 0x22, 0x60, 0xa9, 0x04, 0x24, 0x02, 0xd0, 0x26, 0xa9, 0x01, 0x85,
-        0x02, 0x60, 0xa9, 0x08, 0x24, 0x02, 0xd0, 0x1b, 0xa9, 0x02, 0x85, 0x02, 0x60, 0xa9, 0x01,
-        0x24, 0x02, 0xd0, 0x10, 0xa9, 0x04, 0x85, 0x02, 0x60, 0xa9, 0x02, 0x24, 0x02, 0xd0, 0x05,
-        0xa9, 0x08, 0x85, 0x02, 0x60, 0x60, 0x20, 0x94, 0x06, 0x20, 0xa8, 0x06, 0x60, 0xa5, 0x00,
-        0xc5, 0x10, 0xd0, 0x0d, 0xa5, 0x01, 0xc5, 0x11, 0xd0, 0x07, 0xe6, 0x03, 0xe6, 0x03, 0x20,
-        0x2a, 0x06, 0x60, 0xa2, 0x02, 0xb5, 0x10, 0xc5, 0x10, 0xd0, 0x06, 0xb5, 0x11, 0xc5, 0x11,
-        0xf0, 0x09, 0xe8, 0xe8, 0xe4, 0x03, 0xf0, 0x06, 0x4c, 0xaa, 0x06, 0x4c, 0x35, 0x07, 0x60,
-        0xa6, 0x03, 0xca, 0x8a, 0xb5, 0x10, 0x95, 0x12, 0xca, 0x10, 0xf9, 0xa5, 0x02, 0x4a, 0xb0,
-        0x09, 0x4a, 0xb0, 0x19, 0x4a, 0xb0, 0x1f, 0x4a, 0xb0, 0x2f, 0xa5, 0x10, 0x38, 0xe9, 0x20,
-        0x85, 0x10, 0x90, 0x01, 0x60, 0xc6, 0x11, 0xa9, 0x01, 0xc5, 0x11, 0xf0, 0x28, 0x60, 0xe6,
-        0x10, 0xa9, 0x1f, 0x24, 0x10, 0xf0, 0x1f, 0x60, 0xa5, 0x10, 0x18, 0x69, 0x20, 0x85, 0x10,
-        0xb0, 0x01, 0x60, 0xe6, 0x11, 0xa9, 0x06, 0xc5, 0x11, 0xf0, 0x0c, 0x60, 0xc6, 0x10, 0xa5,
-        0x10, 0x29, 0x1f, 0xc9, 0x1f, 0xf0, 0x01, 0x60, 0x4c, 0x35, 0x07, 0xa0, 0x00, 0xa5, 0xfe,
-        0x91, 0x00, 0x60, 0xa6, 0x03, 0xa9, 0x00, 0x81, 0x10, 0xa2, 0x00, 0xa9, 0x01, 0x81, 0x10,
-        0x60, 0xa6, 0xff, 0xea, 0xea, 0xca, 0xd0, 0xfb, 0x60,
-    ];
+    let options = Options::parse();
+    cli::init_logger(options.log_level);
+
+    let game_code = snake::GAME_CODE.to_vec();
 
+    if let Some(Command::Disasm { rom, start, end }) = &options.command {
+        if let Err(e) = run_disasm(rom.as_deref(), game_code, options.cpu.variant(), *start, *end) {
+            error!("{}", e);
+            std::process::exit(2);
+        }
+        return;
+    }
+
+    if options.command.is_none() && !options.headless && !cfg!(feature = "sdl-frontend") {
+        error!("built without the sdl-frontend feature, only --headless runs are possible");
+        std::process::exit(2);
+    }
 
     //load the game
-    let mut cpu = CPU::new();
-    cpu.load(game_code);
-    cpu.reset();
+    let Program { mut cpu, slots, battery, rom } = match load_program(options.rom.as_deref(), game_code, options.cpu.variant()) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    cpu.detect_infinite_loops = options.detect_loops || options.test_rom;
+    let power_on = SaveState::capture(&cpu, slots.rom_hash, Thumbnail { width: 0, height: 0, pixels: vec![] });
 
-    let mut screen_state = [0 as u8; 32 * 3 * 32];
-    let mut rng = rand::thread_rng();
+    let mut session = Session {
+        slots,
+        battery,
+        pad: KeyboardPad::default(),
+        movie: MovieMode::Off,
+        #[cfg(feature = "sdl-frontend")]
+        rerecord_points: HashMap::new(),
+        power_on,
+        #[cfg(feature = "sdl-frontend")]
+        seed: options.seed,
+        snake: options.rom.is_none().then(|| Rc::new(RefCell::new(SnakeInput::new(options.seed)))),
+        frame_limit: options.frames,
+        #[cfg(feature = "debugger")]
+        debugger: None,
+        cdl: None,
+    };
 
-    // run the game cycle
-    cpu.run_with_callback(move |cpu| {
-        handle_user_input(cpu, &mut event_pump);
+    if options.cdl.is_some() || options.coverage.is_some() {
+        let cdl = match (&rom, &options.rom) {
+            (Some(rom), Some(rom_path)) => CdlOutput::new(rom, rom_path, options.cdl.clone(), options.coverage.clone()),
+            _ => Err("the code/data logger needs a ROM".to_string()),
+        };
+        match cdl {
+            Ok(cdl) => {
+                CodeDataLog::attach(&cdl.log, &mut cpu);
+                session.cdl = Some(cdl);
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
 
-        cpu.mem_write(0xfe, rng.gen_range(1, 16));
+    if let Err(e) = attach_debugger(&mut session, &options) {
+        error!("{}", e);
+        std::process::exit(2);
+    }
 
-        if read_screen_state(cpu, &mut screen_state) {
-            texture.update(None, &screen_state, 32 * 3).unwrap();
+    if let Some(path) = &options.state {
+        if let Err(e) = SaveState::load_from_file(path, session.slots.rom_hash).and_then(|state| state.restore(&mut cpu)) {
+            error!("could not load state: {}", e);
+            std::process::exit(2);
+        }
+    }
 
-            canvas.copy(&texture, None, None).unwrap();
+    let mut nes = match &session.snake {
+        Some(snake) => Nes::with_input(cpu, snake.clone()),
+        None => Nes::new(cpu),
+    };
 
-            canvas.present();
+    if let Some(path) = &options.movie {
+        if let Err(e) = session.start_playback(&mut nes, path) {
+            error!("could not load movie: {}", e);
+            std::process::exit(2);
         }
+    }
+
+    if !options.no_audio && !options.mute {
+        debug!("there is no APU yet, audio output stays silent");
+    }
+
+    if options.headless {
+        run_headless(nes, session, options.test_rom);
+    } else {
+        #[cfg(feature = "sdl-frontend")]
+        sdl::run_sdl(nes, session, &options);
+    }
+}
 
-        std::thread::sleep(std::time::Duration::new(0, 70_000));
-    });
+/// The movies are recorded through the window's keys.
+#[cfg(all(test, feature = "sdl-frontend"))]
+mod test {
+    use super::*;
+    use nes_core::cpu::Mem;
 
-}
\ No newline at end of file
+    /// A snake session whose slots and movie live in the temp directory under `name`.
+    fn snake_session(name: &str, seed: u64) -> (Nes, Session) {
+        let base = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let Program { cpu, slots, .. } = load_program(None, snake::GAME_CODE.to_vec(), CpuVariant::Ricoh2A03).unwrap();
+        let power_on = SaveState::capture(&cpu, slots.rom_hash, Thumbnail { width: 0, height: 0, pixels: vec![] });
+        let session = Session {
+            slots: StateSlots { base, ..slots },
+            battery: None,
+            pad: KeyboardPad::default(),
+            movie: MovieMode::Off,
+            #[cfg(feature = "sdl-frontend")]
+            rerecord_points: HashMap::new(),
+            power_on,
+            #[cfg(feature = "sdl-frontend")]
+            seed,
+            snake: Some(Rc::new(RefCell::new(SnakeInput::new(seed)))),
+            frame_limit: None,
+            #[cfg(feature = "debugger")]
+            debugger: None,
+            cdl: None,
+        };
+        let nes = Nes::with_input(cpu, session.snake.clone().unwrap());
+        (nes, session)
+    }
+
+    /// Runs `frames` frames holding `buttons`, as the frontends do.
+    fn run_frames(nes: &mut Nes, session: &mut Session, frames: usize, buttons: JoypadButton) {
+        for _ in 0..frames {
+            session.pad.held = buttons;
+            let buttons = session.next_input(nes);
+            nes.set_input(buttons);
+            nes.run_frame().unwrap();
+        }
+    }
+
+    fn memory(nes: &Nes) -> Vec<u8> {
+        (0..=0xffff).map(|addr| nes.cpu().mem_peek(addr)).collect()
+    }
+
+    #[test]
+    fn test_rerecord_from_slot() {
+        let (mut nes, mut session) = snake_session("nes-rerecord", 3);
+        run_frames(&mut nes, &mut session, 7, JoypadButton::empty());
+
+        session.toggle_recording(&mut nes);
+        run_frames(&mut nes, &mut session, 5, JoypadButton::DOWN);
+        session.save_slot(&nes, &[0; 32 * 32 * 3]);
+        run_frames(&mut nes, &mut session, 5, JoypadButton::LEFT);
+        session.load_slot(&mut nes);
+        run_frames(&mut nes, &mut session, 5, JoypadButton::RIGHT);
+        let recorded = memory(&nes);
+
+        match &session.movie {
+            MovieMode::Recording(movie) => {
+                assert_eq!(movie.rerecord_count, 1);
+                assert_eq!(movie.frames.len(), 10);
+                assert_eq!(movie.input(5), Some(JoypadButton::RIGHT));
+            }
+            _ => panic!("not recording"),
+        }
+        session.toggle_recording(&mut nes);
+
+        let path = session.slots.base.with_extension("fm2");
+        session.start_playback(&mut nes, &path).unwrap();
+        run_frames(&mut nes, &mut session, 10, JoypadButton::UP);
+        assert_eq!(memory(&nes), recorded);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(session.slots.path()).unwrap();
+    }
+
+    #[test]
+    fn test_playback_repeats_recording() {
+        let (mut nes, mut session) = snake_session("nes-playback", 11);
+        run_frames(&mut nes, &mut session, 13, JoypadButton::empty());
+
+        session.toggle_recording(&mut nes);
+        let turns = [JoypadButton::DOWN, JoypadButton::LEFT, JoypadButton::UP, JoypadButton::RIGHT];
+        for buttons in turns.iter().cycle().take(24) {
+            run_frames(&mut nes, &mut session, 5, *buttons);
+        }
+        let recorded = memory(&nes);
+        session.toggle_recording(&mut nes);
+
+        // another seed and a machine that ran on; the movie brings its own
+        let (mut other, mut other_session) = snake_session("nes-playback-other", 99);
+        run_frames(&mut other, &mut other_session, 40, JoypadButton::UP);
+        let path = session.slots.base.with_extension("fm2");
+        other_session.start_playback(&mut other, &path).unwrap();
+        run_frames(&mut other, &mut other_session, 120, JoypadButton::empty());
+        assert_eq!(memory(&other), recorded);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
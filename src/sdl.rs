@@ -0,0 +1,257 @@
+use crate::cli::Options;
+use crate::Session;
+use nes_core::cpu::CPU;
+use nes_core::joypad::JoypadButton;
+use nes_core::nes::Nes;
+use nes_core::rewind::Rewind;
+use nes_core::snake;
+#[cfg(feature = "audio")]
+use sdl2::audio::AudioQueue;
+#[cfg(feature = "audio")]
+use sdl2::audio::AudioSpecDesired;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::keyboard::Scancode;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Texture;
+use sdl2::render::WindowCanvas;
+#[cfg(feature = "audio")]
+use sdl2::Sdl;
+use sdl2::EventPump;
+use std::time::Duration;
+use std::time::Instant;
+
+#[cfg(feature = "audio")]
+const AUDIO_SAMPLE_RATE: i32 = 44_100;
+
+fn present_screen(canvas: &mut WindowCanvas, texture: &mut Texture, screen_state: &[u8]) {
+    texture.update(None, screen_state, 32 * 3).unwrap();
+
+    canvas.copy(texture, None, None).unwrap();
+
+    canvas.present();
+}
+
+fn slot_key(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::Num0 => Some(0),
+        Keycode::Num1 => Some(1),
+        Keycode::Num2 => Some(2),
+        Keycode::Num3 => Some(3),
+        Keycode::Num4 => Some(4),
+        Keycode::Num5 => Some(5),
+        Keycode::Num6 => Some(6),
+        Keycode::Num7 => Some(7),
+        Keycode::Num8 => Some(8),
+        Keycode::Num9 => Some(9),
+        _ => None,
+    }
+}
+
+fn key_button(keycode: Keycode) -> Option<JoypadButton> {
+    match keycode {
+        Keycode::W => Some(JoypadButton::UP),
+        Keycode::S => Some(JoypadButton::DOWN),
+        Keycode::A => Some(JoypadButton::LEFT),
+        Keycode::D => Some(JoypadButton::RIGHT),
+        _ => None,
+    }
+}
+
+/// Returns `false` when the user asked to quit.
+fn handle_user_input(
+    nes: &mut Nes,
+    event_pump: &mut EventPump,
+    session: &mut Session,
+    screen_state: &[u8],
+) -> bool {
+    for event in event_pump.poll_iter() {
+        match event {
+            Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                return false;
+            },
+            Event::KeyDown { keycode: Some(Keycode::F5), .. } => {
+                session.save_slot(nes, screen_state);
+            },
+            Event::KeyDown { keycode: Some(Keycode::F7), .. } => {
+                session.load_slot(nes);
+            },
+            Event::KeyDown { keycode: Some(Keycode::F9), .. } => {
+                session.toggle_recording(nes);
+            },
+            Event::KeyDown { keycode: Some(Keycode::F10), .. } => {
+                let path = session.slots.base.with_extension("fm2");
+                if let Err(e) = session.start_playback(nes, &path) {
+                    error!("could not load movie: {}", e);
+                }
+            },
+            Event::KeyDown { keycode: Some(keycode), .. } if slot_key(keycode).is_some() => {
+                session.slots.slot = slot_key(keycode).unwrap();
+            },
+            Event::KeyDown { keycode: Some(keycode), .. } if key_button(keycode).is_some() => {
+                let button = key_button(keycode).unwrap();
+                session.pad.held.insert(button);
+                session.pad.tapped.insert(button);
+            },
+            Event::KeyUp { keycode: Some(keycode), .. } if key_button(keycode).is_some() => {
+                session.pad.held.remove(key_button(keycode).unwrap());
+            }
+            _ => {/* do nothing */}
+        }
+    }
+    true
+}
+
+/// Like `Session::debug`, but keeps the window responsive while paused.
+#[cfg(feature = "debugger")]
+fn debug_sdl(
+    cpu: &mut CPU,
+    session: &mut Session,
+    event_pump: &mut EventPump,
+    canvas: &mut WindowCanvas,
+    texture: &mut Texture,
+    screen_state: &mut [u8; 32 * 3 * 32],
+) {
+    let debugger = match &mut session.debugger {
+        Some(debugger) => debugger,
+        None => return,
+    };
+    let mut closed = false;
+    if debugger.before_instruction(cpu) {
+        while debugger.is_paused() && !debugger.quit_requested() && !closed {
+            debugger.poll(cpu);
+            closed = event_pump.poll_iter().any(|event| matches!(event, Event::Quit { .. }));
+            if snake::render(cpu, screen_state) {
+                present_screen(canvas, texture, screen_state);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    if closed || debugger.quit_requested() {
+        session.quit(cpu, 0);
+    }
+}
+
+#[cfg(not(feature = "debugger"))]
+fn debug_sdl(
+    _cpu: &mut CPU,
+    _session: &mut Session,
+    _event_pump: &mut EventPump,
+    _canvas: &mut WindowCanvas,
+    _texture: &mut Texture,
+    _screen_state: &mut [u8; 32 * 3 * 32],
+) {
+}
+
+/// Opens the output device, paused when muted. There is no APU yet, so frames bring no
+/// samples and it plays silence.
+#[cfg(feature = "audio")]
+fn open_audio(sdl_context: &Sdl, mute: bool) -> Option<AudioQueue<i16>> {
+    let desired = AudioSpecDesired { freq: Some(AUDIO_SAMPLE_RATE), channels: Some(1), samples: None };
+    let queue = sdl_context.audio().and_then(|audio| audio.open_queue::<i16, _>(None, &desired));
+    match queue {
+        Ok(queue) => {
+            if !mute {
+                queue.resume();
+            }
+            Some(queue)
+        }
+        Err(e) => {
+            warn!("could not open an audio device: {}", e);
+            None
+        }
+    }
+}
+
+pub fn run_sdl(mut nes: Nes, mut session: Session, options: &Options) {
+    // init sdl2
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let title = match &options.rom {
+        Some(rom_path) => rom_path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        None => "Snake game".to_string(),
+    };
+    let mut window = video_subsystem.window(&title, 32 * options.scale, 32 * options.scale);
+    window.position_centered();
+    if options.fullscreen {
+        window.fullscreen_desktop();
+    }
+    let window = window.build().unwrap();
+
+    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    let mut event_pump = sdl_context.event_pump().unwrap();
+    canvas.set_scale(options.scale as f32, options.scale as f32).unwrap();
+
+    #[cfg(feature = "audio")]
+    let audio = if options.no_audio { None } else { open_audio(&sdl_context, options.mute) };
+
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_target(PixelFormatEnum::RGB24, 32, 32).unwrap();
+
+    let mut screen_state = [0_u8; 32 * 3 * 32];
+    let rewind_budget = usize::try_from(options.rewind_budget << 20).unwrap_or(usize::MAX);
+    let mut rewind = Rewind::new(options.rewind_interval as usize, rewind_budget);
+    let frame_duration = Duration::from_secs_f64(1.0 / options.region.frame_rate());
+    let mut next_frame = Instant::now() + frame_duration;
+
+    // run the game cycle
+    let result = loop {
+        if !handle_user_input(&mut nes, &mut event_pump, &mut session, &screen_state) {
+            session.quit(nes.cpu(), 0);
+        }
+
+        // hold backspace to play the game backwards
+        while session.movie.is_off() && event_pump.keyboard_state().is_scancode_pressed(Scancode::Backspace) {
+            if let Err(e) = rewind.rewind(nes.cpu_mut()) {
+                warn!("could not rewind: {}", e);
+                break;
+            }
+            if snake::render(nes.cpu(), &mut screen_state) {
+                present_screen(&mut canvas, &mut texture, &screen_state);
+            }
+            std::thread::sleep(frame_duration * options.rewind_interval);
+            event_pump.pump_events();
+        }
+
+        let buttons = session.next_input(&mut nes);
+        nes.set_input(buttons);
+        let frame = nes.run_frame_with_callback(|cpu| {
+            debug_sdl(cpu, &mut session, &mut event_pump, &mut canvas, &mut texture, &mut screen_state);
+        });
+        match frame {
+            Ok(frame) => {
+                screen_state.copy_from_slice(frame.pixels);
+                #[cfg(feature = "audio")]
+                if let Some(audio) = &audio {
+                    let samples: Vec<i16> =
+                        frame.samples.iter().map(|sample| (sample * i16::MAX as f32) as i16).collect();
+                    if !audio.queue(&samples) {
+                        warn!("could not queue audio: {}", sdl2::get_error());
+                    }
+                }
+            }
+            Err(e) => break Err(e),
+        }
+        present_screen(&mut canvas, &mut texture, &screen_state);
+        if let Some(reason) = nes.cpu().halt_reason() {
+            break Ok(reason);
+        }
+
+        rewind.on_frame(nes.cpu());
+        session.frame_done(&nes);
+        if session.frame_limit_reached(&nes) {
+            session.quit(nes.cpu(), 0);
+        }
+
+        // throttle to the region's frame rate
+        let now = Instant::now();
+        if now < next_frame {
+            std::thread::sleep(next_frame - now);
+            next_frame += frame_duration;
+        } else {
+            next_frame = now + frame_duration;
+        }
+    };
+    session.stop(nes.cpu(), result);
+}